@@ -0,0 +1,291 @@
+//! Files attached to notifications with the `x-kde-urls` hint, e.g. by screenshot tools.
+//!
+//! Attachments are shown as thumbnails in the toast. Thumbnails come from the freedesktop
+//! thumbnail cache when the file manager already made one for the current version of the file,
+//! or are generated with gdk-pixbuf for images and written to the cache for other apps. Other
+//! files get the icon of their content type, which is also shown while the thumbnail loads.
+//!
+//! Thumbnails are loaded in the background, and kept in memory so that rebuilding a toast doesn't
+//! load them again.
+//!
+//! See https://specifications.freedesktop.org/thumbnail-spec/latest/
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use color_eyre::Result;
+use gio::prelude::{FileExt, OutputStreamExt};
+use gtk::gdk_pixbuf::{InterpType, Pixbuf};
+use gtk::prelude::{BoxExt, Cast, GestureExt, ObjectExt, ToValue, WidgetExt};
+use tracing::{debug, warn};
+use zvariant::Value;
+
+/// Number of thumbnails shown in a toast.
+const MAX_SHOWN: usize = 4;
+
+/// Size of the thumbnails in the `large` directory of the cache, the ones shizuku generates.
+const LARGE_SIZE: i32 = 256;
+
+/// Number of thumbnails kept in memory.
+const MAX_LOADED: usize = 64;
+
+thread_local! {
+    /// Thumbnails loaded so far by URI, with the mtime of the file they show. [None] if the file
+    /// has no thumbnail.
+    static LOADED: RefCell<HashMap<String, (u64, Option<Pixbuf>)>> = RefCell::new(HashMap::new());
+}
+
+/// Reads the URIs in an `x-kde-urls` hint (`as`).
+pub fn from_hint(value: &Value<'_>) -> Vec<String> {
+    let Value::Array(urls) = value else {
+        warn!(?value, "Invalid x-kde-urls hint");
+        return Vec::new();
+    };
+    (urls.iter())
+        .filter_map(|url| url.downcast_ref::<str>())
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn thumbnail_dir() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_default()
+        .join("thumbnails")
+}
+
+/// File name of the thumbnail of `uri` in the thumbnail cache: the MD5 of the URI.
+fn thumbnail_name(uri: &str) -> String {
+    let hash = glib::compute_checksum_for_string(glib::ChecksumType::Md5, uri).unwrap_or_default();
+    format!("{hash}.png")
+}
+
+/// Modification time of a local file in secs, as stored in `Thumb::MTime`.
+fn mtime(uri: &str) -> Option<u64> {
+    let path = gio::File::for_uri(uri).path()?;
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Reads an image, at most `size` pixels wide and high, decoding it off the main thread.
+async fn read_pixbuf(path: &Path, size: i32) -> Option<Pixbuf> {
+    let read = async {
+        let stream = gio::File::for_path(path)
+            .read_future(glib::Priority::LOW)
+            .await?;
+        Pixbuf::from_stream_at_scale_future(&stream, size, size, true).await
+    };
+    read.await
+        .map_err(|e| debug!(?e, ?path, "Cannot read image"))
+        .ok()
+}
+
+/// A thumbnail of `uri` from the cache, if it was made for the version of the file at `mtime`.
+async fn cached_thumbnail(uri: &str, mtime: u64) -> Option<Pixbuf> {
+    for dir in ["large", "normal"] {
+        let path = thumbnail_dir().join(dir).join(thumbnail_name(uri));
+        if !path.is_file() {
+            continue;
+        }
+        let Some(pixbuf) = read_pixbuf(&path, LARGE_SIZE).await else {
+            continue;
+        };
+        let made_for = (pixbuf.option("tEXt::Thumb::MTime")).and_then(|m| m.parse::<u64>().ok());
+        if made_for == Some(mtime) {
+            return Some(pixbuf);
+        }
+        debug!(?path, made_for, mtime, "Ignoring outdated thumbnail");
+    }
+    None
+}
+
+/// Writes a thumbnail of `uri` to the `large` directory of the cache.
+async fn save_thumbnail(uri: &str, mtime: u64, pixbuf: &Pixbuf) -> Result<()> {
+    let dir = thumbnail_dir().join("large");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    // write then rename, so that other apps never read a half-written thumbnail
+    let path = dir.join(thumbnail_name(uri));
+    let tmp = path.with_extension(format!("png.shizuku-{}", std::process::id()));
+    let stream = gio::File::for_path(&tmp)
+        .replace_future(
+            None,
+            false,
+            gio::FileCreateFlags::PRIVATE,
+            glib::Priority::LOW,
+        )
+        .await?;
+    let mtime = mtime.to_string();
+    let options = [
+        ("tEXt::Thumb::URI", uri),
+        ("tEXt::Thumb::MTime", mtime.as_str()),
+        ("tEXt::Software", "shizuku"),
+    ];
+    pixbuf
+        .save_to_streamv_future(&stream, "png", &options)
+        .await?;
+    stream.close_future(glib::Priority::LOW).await?;
+    std::fs::rename(&tmp, &path)?;
+    debug!(?path, "Saved thumbnail");
+    Ok(())
+}
+
+/// Loads the thumbnail of `uri` from the cache, or generates it if the file is an image.
+async fn load_thumbnail(uri: String, mtime: u64) -> Option<Pixbuf> {
+    let pixbuf = match cached_thumbnail(&uri, mtime).await {
+        Some(pixbuf) => Some(pixbuf),
+        None => {
+            let pixbuf = match gio::File::for_uri(&uri).path() {
+                Some(path) => read_pixbuf(&path, LARGE_SIZE).await,
+                None => None,
+            };
+            if let Some(pixbuf) = &pixbuf {
+                if let Err(e) = save_thumbnail(&uri, mtime, pixbuf).await {
+                    warn!(?e, uri, "Cannot save thumbnail");
+                }
+            }
+            pixbuf
+        }
+    };
+    LOADED.with(|loaded| {
+        let mut loaded = loaded.borrow_mut();
+        if loaded.len() >= MAX_LOADED {
+            loaded.clear();
+        }
+        loaded.insert(uri, (mtime, pixbuf.clone()));
+    });
+    pixbuf
+}
+
+/// Scales `pixbuf` down to at most `size` pixels wide and high.
+fn fit(pixbuf: &Pixbuf, size: i32) -> Pixbuf {
+    let (width, height) = (pixbuf.width(), pixbuf.height());
+    if width <= size && height <= size {
+        return pixbuf.clone();
+    }
+    let scale = f64::from(size) / f64::from(width.max(height));
+    #[allow(clippy::cast_possible_truncation)]
+    let scaled = |len: i32| ((f64::from(len) * scale).round() as i32).max(1);
+    (pixbuf.scale_simple(scaled(width), scaled(height), InterpType::Bilinear))
+        .unwrap_or_else(|| pixbuf.clone())
+}
+
+/// Builds the thumbnail of a single attachment.
+///
+/// Clicking it opens the file with its default handler, and it can be dragged out to other apps.
+fn build_thumbnail(uri: &str, size: i32) -> gtk::Widget {
+    let file = gio::File::for_uri(uri);
+    let name = (file.basename())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| uri.to_string());
+
+    let image = gtk::Image::builder()
+        .pixel_size(size)
+        .tooltip_text(&name)
+        .css_classes(vec!["notif-attachment"])
+        .build();
+    let (content_type, _) = gio::content_type_guess(Some(&name), &[]);
+    image.set_from_gicon(&gio::content_type_get_icon(&content_type));
+
+    let click = gtk::GestureClick::new();
+    let uri_ = uri.to_string();
+    click.connect_released(move |gesture, _, _, _| {
+        // don't expand the toast
+        gesture.set_state(gtk::EventSequenceState::Claimed);
+        debug!(uri = uri_, "Opening attachment");
+        if let Err(e) = gio::AppInfo::launch_default_for_uri(&uri_, None::<&gio::AppLaunchContext>)
+        {
+            warn!(?e, uri = uri_, "Cannot open attachment");
+        }
+    });
+    image.add_controller(click);
+
+    let drag = gtk::DragSource::builder()
+        .actions(gtk::gdk::DragAction::COPY)
+        .content(&gtk::gdk::ContentProvider::for_value(&file.to_value()))
+        .build();
+    image.add_controller(drag.clone());
+
+    if let Some(mtime) = mtime(uri) {
+        let (weak, uri) = (image.downgrade(), uri.to_string());
+        let show = move |pixbuf: &Pixbuf| {
+            let Some(image) = weak.upgrade() else {
+                return;
+            };
+            let pixbuf = fit(pixbuf, size);
+            image.set_from_pixbuf(Some(&pixbuf));
+            drag.set_icon(Some(&gtk::gdk::Texture::for_pixbuf(&pixbuf)), 0, 0);
+        };
+        let loaded = LOADED.with(|loaded| {
+            (loaded.borrow().get(&uri))
+                .filter(|(loaded_mtime, _)| *loaded_mtime == mtime)
+                .map(|(_, pixbuf)| pixbuf.clone())
+        });
+        match loaded {
+            Some(Some(pixbuf)) => show(&pixbuf),
+            // loaded before, there is no thumbnail
+            Some(None) => {}
+            None => {
+                glib::MainContext::default().spawn_local(async move {
+                    if let Some(pixbuf) = load_thumbnail(uri, mtime).await {
+                        show(&pixbuf);
+                    }
+                });
+            }
+        }
+    }
+
+    image.upcast()
+}
+
+/// Builds the row of thumbnails for the attachments of a notif.
+pub fn build_attachments(uris: &[String], size: i32) -> gtk::Box {
+    let row = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(10)
+        .build();
+    for uri in uris.iter().take(MAX_SHOWN) {
+        row.append(&build_thumbnail(uri, size));
+    }
+    if uris.len() > MAX_SHOWN {
+        row.append(
+            &gtk::Label::builder()
+                .label(format!("+{}", uris.len() - MAX_SHOWN))
+                .valign(gtk::Align::Center)
+                .build(),
+        );
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thumbnail_name_from_spec() {
+        assert_eq!(
+            thumbnail_name("file:///home/jens/photos/me.png"),
+            "c6ee772d9e49320e97ec29a7eb5b1697.png"
+        );
+    }
+
+    #[test]
+    fn fit_thumbnails() {
+        let pixbuf = |w, h| Pixbuf::new(gtk::gdk_pixbuf::Colorspace::Rgb, false, 8, w, h).unwrap();
+        let fitted = fit(&pixbuf(256, 128), 64);
+        assert_eq!((fitted.width(), fitted.height()), (64, 32));
+        let fitted = fit(&pixbuf(10, 300), 64);
+        assert_eq!((fitted.width(), fitted.height()), (2, 64));
+        let fitted = fit(&pixbuf(32, 32), 64);
+        assert_eq!((fitted.width(), fitted.height()), (32, 32));
+    }
+}
@@ -0,0 +1,118 @@
+//! Audit trail of notifications in the systemd journal.
+//!
+//! When enabled, every delivered notification, and how it was closed or acted upon, is written to
+//! the journal as structured fields (`SHIZUKU_APP`, `SHIZUKU_SUMMARY`, `SHIZUKU_URGENCY`,
+//! `SHIZUKU_CATEGORY`, `SHIZUKU_ID`, `SHIZUKU_CLOSE_REASON`, `SHIZUKU_ACTION`…) under the
+//! `shizuku-audit` syslog identifier. Notifications that replace one on screen, or that are not
+//! shown because of Do Not Disturb or a muted app, are recorded as `updated`, `dnd-suppressed`
+//! and `muted` events instead of `delivered`:
+//!
+//! ```toml
+//! [audit]
+//! enabled = true
+//! redact_body = true
+//! ```
+//!
+//! ```sh
+//! journalctl --user -t shizuku-audit -o verbose
+//! ```
+//!
+//! Audit records are `tracing` events with the [TARGET] target, which only the [layer] picks up.
+use serde::Deserialize;
+use tracing::{info, warn, Level};
+use tracing_subscriber::{filter::Targets, Layer};
+
+use crate::{dbus::CloseReason, stack::Added, widget::Notification};
+
+/// Target of the audit events, see [layer].
+pub const TARGET: &str = "shizuku::audit";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Leave the body out of the journal, as it may contain private messages.
+    pub redact_body: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            redact_body: true,
+        }
+    }
+}
+
+/// The journald layer that audit events go to. Other events are ignored by this layer.
+///
+/// [None] if audit is disabled or the journal is not available. The config must be loaded first.
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    enabled()?;
+    let journald = (tracing_journald::layer())
+        .map_err(|e| warn!(?e, "Cannot connect to the journal, audit is disabled"))
+        .ok()?;
+    Some(
+        journald
+            .with_syslog_identifier("shizuku-audit".to_string())
+            .with_field_prefix(Some("SHIZUKU".to_string()))
+            .with_filter(Targets::new().with_target(TARGET, Level::INFO)),
+    )
+}
+
+fn enabled() -> Option<bool> {
+    let config = crate::config::get();
+    config.audit.enabled.then_some(config.audit.redact_body)
+}
+
+pub fn is_enabled() -> bool {
+    enabled().is_some()
+}
+
+/// Records a notification being delivered to the stack, and what became of it.
+pub fn delivered(notif: &Notification, added: Added) {
+    let Some(redact_body) = enabled() else {
+        return;
+    };
+    let (event, message) = match added {
+        Added::Shown => ("delivered", "Notification delivered"),
+        Added::Updated => ("updated", "Notification updated"),
+        Added::DoNotDisturb => ("dnd-suppressed", "Notification held back by DND"),
+        Added::Muted => ("muted", "Notification of a muted app"),
+    };
+    let body = if redact_body {
+        "[redacted]"
+    } else {
+        notif.body.as_str()
+    };
+    info!(
+        target: TARGET,
+        event,
+        id = notif.id,
+        app = %notif.app_name,
+        summary = %notif.title,
+        body,
+        urgency = ?notif.urgency,
+        category = notif.category.as_deref().unwrap_or_default(),
+        "{message}"
+    );
+}
+
+/// Records a notification being closed.
+pub fn closed(id: u32, reason: CloseReason) {
+    if enabled().is_none() {
+        return;
+    }
+    info!(target: TARGET, event = "closed", id, close_reason = ?reason, "Notification closed");
+}
+
+/// Records an action of a notification being invoked.
+pub fn action_invoked(id: u32, action: &str) {
+    if enabled().is_none() {
+        return;
+    }
+    info!(target: TARGET, event = "action", id, action, "Notification action invoked");
+}
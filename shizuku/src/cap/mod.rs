@@ -0,0 +1,356 @@
+//! Common Alerting Protocol (CAP) 1.2 emergency alerts.
+//!
+//! CAP alerts are shown as notifications that stay on screen until the alert expires, and that
+//! bypass Do Not Disturb. Someone's life might be at stake, after all.
+//!
+//! See <https://docs.oasis-open.org/emergency/cap/v1.2/CAP-v1.2.html> for the format, and
+//! [source] for where alerts come from.
+use chrono::{DateTime, FixedOffset};
+use color_eyre::{
+    eyre::{eyre, OptionExt},
+    Result,
+};
+use roxmltree::Node;
+
+use crate::dbus::Urgency;
+
+pub mod source;
+
+/// Notification category of CAP alerts.
+pub const CATEGORY: &str = "x-shizuku.cap";
+
+/// Nature of an alert message (`<msgType>`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsgType {
+    Alert,
+    /// Supersedes the alerts in [Alert::references].
+    Update,
+    /// Cancels the alerts in [Alert::references].
+    Cancel,
+    Ack,
+    Error,
+}
+
+/// Time available to prepare (`<urgency>`), not to be confused with the notification [Urgency].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapUrgency {
+    Immediate,
+    Expected,
+    Future,
+    Past,
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Extreme,
+    Severe,
+    Moderate,
+    Minor,
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Certainty {
+    Observed,
+    Likely,
+    Possible,
+    Unlikely,
+    Unknown,
+}
+
+/// One `<info>` block of an alert. Alerts have one per language.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub language: String,
+    pub event: String,
+    pub urgency: CapUrgency,
+    pub severity: Severity,
+    pub certainty: Certainty,
+    pub sender_name: Option<String>,
+    pub headline: Option<String>,
+    pub description: Option<String>,
+    pub instruction: Option<String>,
+    pub expires: Option<DateTime<FixedOffset>>,
+    /// `<areaDesc>` of every `<area>`.
+    pub areas: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Alert {
+    pub identifier: String,
+    pub sender: String,
+    pub sent: DateTime<FixedOffset>,
+    /// `Actual`, `Exercise`, `System`, `Test` or `Draft`.
+    pub status: String,
+    pub msg_type: MsgType,
+    /// Identifiers of the earlier alerts this one updates or cancels.
+    pub references: Vec<String>,
+    pub info: Vec<Info>,
+}
+
+/// Finds a child element by its local name; alerts in the wild don't always use the namespace.
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn text(node: Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|n| n.text())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+fn required(node: Node, name: &'static str) -> Result<String> {
+    text(node, name).ok_or_else(|| eyre!("missing <{name}>"))
+}
+
+fn time(s: &str) -> Result<DateTime<FixedOffset>> {
+    Ok(DateTime::parse_from_rfc3339(s)?)
+}
+
+impl Info {
+    fn from_node(node: Node) -> Result<Self> {
+        let urgency = match required(node, "urgency")?.as_str() {
+            "Immediate" => CapUrgency::Immediate,
+            "Expected" => CapUrgency::Expected,
+            "Future" => CapUrgency::Future,
+            "Past" => CapUrgency::Past,
+            _ => CapUrgency::Unknown,
+        };
+        let severity = match required(node, "severity")?.as_str() {
+            "Extreme" => Severity::Extreme,
+            "Severe" => Severity::Severe,
+            "Moderate" => Severity::Moderate,
+            "Minor" => Severity::Minor,
+            _ => Severity::Unknown,
+        };
+        let certainty = match required(node, "certainty")?.as_str() {
+            "Observed" => Certainty::Observed,
+            // "Very Likely" is from CAP 1.0 and should be treated as "Likely"
+            "Likely" | "Very Likely" => Certainty::Likely,
+            "Possible" => Certainty::Possible,
+            "Unlikely" => Certainty::Unlikely,
+            _ => Certainty::Unknown,
+        };
+        Ok(Self {
+            language: text(node, "language").unwrap_or_else(|| "en-US".to_string()),
+            event: required(node, "event")?,
+            urgency,
+            severity,
+            certainty,
+            sender_name: text(node, "senderName"),
+            headline: text(node, "headline"),
+            description: text(node, "description"),
+            instruction: text(node, "instruction"),
+            expires: text(node, "expires").as_deref().map(time).transpose()?,
+            areas: (node.children())
+                .filter(|n| n.tag_name().name() == "area")
+                .filter_map(|area| text(area, "areaDesc"))
+                .collect(),
+        })
+    }
+
+    /// Maps severity, urgency and certainty to the urgency of the notification.
+    ///
+    /// Only severe alerts that need action now and are likely to happen are critical.
+    pub const fn notif_urgency(&self) -> Urgency {
+        use {CapUrgency as U, Certainty as C, Severity as S};
+        match (self.severity, self.urgency, self.certainty) {
+            (S::Extreme | S::Severe, U::Immediate | U::Expected, C::Observed | C::Likely) => {
+                Urgency::Critical
+            }
+            (S::Minor | S::Unknown, _, _) | (_, U::Past, _) | (_, _, C::Unlikely) => Urgency::Low,
+            _ => Urgency::Normal,
+        }
+    }
+}
+
+impl Alert {
+    /// Parses a CAP document.
+    pub fn parse(xml: &str) -> Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        Self::from_node(doc.root_element())
+    }
+
+    /// Parses an `<alert>` element, e.g. one embedded in an Atom feed.
+    pub fn from_node(node: Node) -> Result<Self> {
+        if node.tag_name().name() != "alert" {
+            return Err(eyre!("expected <alert>, got <{}>", node.tag_name().name()));
+        }
+        let msg_type = match required(node, "msgType")?.as_str() {
+            "Alert" => MsgType::Alert,
+            "Update" => MsgType::Update,
+            "Cancel" => MsgType::Cancel,
+            "Ack" => MsgType::Ack,
+            "Error" => MsgType::Error,
+            other => return Err(eyre!("unknown <msgType> {other}")),
+        };
+        Ok(Self {
+            identifier: required(node, "identifier")?,
+            sender: required(node, "sender")?,
+            sent: time(&required(node, "sent")?)?,
+            status: required(node, "status")?,
+            msg_type,
+            // space separated `sender,identifier,sent` triplets
+            references: (text(node, "references").unwrap_or_default())
+                .split_whitespace()
+                .filter_map(|r| r.split(',').nth(1))
+                .map(str::to_string)
+                .collect(),
+            info: (node.children())
+                .filter(|n| n.tag_name().name() == "info")
+                .map(Info::from_node)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Picks the `<info>` in the language of the user, or the first one.
+    pub fn preferred_info(&self) -> Option<&Info> {
+        let lang = (std::env::var("LANG").ok())
+            .and_then(|lang| lang.split(['_', '.', '-']).next().map(str::to_lowercase))
+            .unwrap_or_default();
+        (self.info.iter())
+            .find(|info| info.language.to_lowercase().starts_with(&lang) && !lang.is_empty())
+            .or_else(|| self.info.first())
+    }
+
+    /// The latest expiry of the alert, or [None] if it doesn't expire.
+    pub fn expires(&self) -> Option<DateTime<FixedOffset>> {
+        if self.info.iter().any(|info| info.expires.is_none()) {
+            return None;
+        }
+        self.info.iter().filter_map(|info| info.expires).max()
+    }
+
+    /// Builds the notification shown for this alert.
+    pub fn to_notification(&self, id: u32) -> Result<crate::widget::Notification> {
+        let info = self.preferred_info().ok_or_eyre("alert has no <info>")?;
+        let mut body = (info.description.clone())
+            .into_iter()
+            .chain(info.instruction.clone())
+            .collect::<Vec<_>>();
+        if !info.areas.is_empty() {
+            body.push(info.areas.join(", "));
+        }
+        let sched = match self.expires() {
+            #[allow(clippy::cast_sign_loss)]
+            Some(expires) => crate::NotifSchedTimer::until(expires.timestamp_millis() as u128),
+            None => crate::NotifSchedTimer::persistent(),
+        };
+        Ok(crate::widget::Notification {
+            app_name: (info.sender_name.clone()).unwrap_or_else(|| self.sender.clone()),
+            title: glib::markup_escape_text(info.headline.as_ref().unwrap_or(&info.event)).into(),
+            body: glib::markup_escape_text(&body.join("\n\n")).into(),
+            icon: Some("dialog-warning".to_string()),
+            urgency: info.notif_urgency(),
+            category: Some(CATEGORY.to_string()),
+            id,
+            sched,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub const ALERT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<alert xmlns="urn:oasis:names:tc:emergency:cap:1.2">
+  <identifier>KSTO1055887203</identifier>
+  <sender>KSTO@NWS.NOAA.GOV</sender>
+  <sent>2003-06-17T14:57:00-07:00</sent>
+  <status>Actual</status>
+  <msgType>Alert</msgType>
+  <scope>Public</scope>
+  <info>
+    <category>Met</category>
+    <event>SEVERE THUNDERSTORM</event>
+    <urgency>Immediate</urgency>
+    <severity>Severe</severity>
+    <certainty>Observed</certainty>
+    <expires>2003-06-17T16:00:00-07:00</expires>
+    <senderName>NATIONAL WEATHER SERVICE SACRAMENTO CA</senderName>
+    <headline>SEVERE THUNDERSTORM WARNING</headline>
+    <description>AT 254 PM PDT...A SEVERE THUNDERSTORM WAS LOCATED 8 MILES NORTHEAST OF COLUSA.</description>
+    <instruction>TAKE COVER &amp; STAY INDOORS.</instruction>
+    <area>
+      <areaDesc>EXTREME NORTH CENTRAL TUOLUMNE COUNTY IN CALIFORNIA</areaDesc>
+    </area>
+  </info>
+</alert>"#;
+
+    #[test]
+    fn parse_alert() {
+        let alert = Alert::parse(ALERT).unwrap();
+        assert_eq!(alert.identifier, "KSTO1055887203");
+        assert_eq!(alert.msg_type, MsgType::Alert);
+        assert_eq!(alert.info.len(), 1);
+        let info = &alert.info[0];
+        assert_eq!(info.event, "SEVERE THUNDERSTORM");
+        assert_eq!(
+            info.instruction.as_deref(),
+            Some("TAKE COVER & STAY INDOORS.")
+        );
+        assert_eq!(info.areas.len(), 1);
+        assert_eq!(info.notif_urgency(), Urgency::Critical);
+        assert_eq!(
+            alert.expires().unwrap().to_rfc3339(),
+            "2003-06-17T16:00:00-07:00"
+        );
+    }
+
+    #[test]
+    fn parse_cancel_references() {
+        let cancel = ALERT
+            .replace("<msgType>Alert</msgType>", "<msgType>Cancel</msgType><references>KSTO@NWS.NOAA.GOV,KSTO1055887200,2003-06-17T14:00:00-07:00 KSTO@NWS.NOAA.GOV,KSTO1055887201,2003-06-17T14:30:00-07:00</references>");
+        let alert = Alert::parse(&cancel).unwrap();
+        assert_eq!(alert.msg_type, MsgType::Cancel);
+        assert_eq!(alert.references, ["KSTO1055887200", "KSTO1055887201"]);
+    }
+
+    #[test]
+    fn map_urgency() {
+        let info = |severity, urgency, certainty| Info {
+            severity,
+            urgency,
+            certainty,
+            ..Alert::parse(ALERT).unwrap().info.remove(0)
+        };
+        use {CapUrgency as U, Certainty as C, Severity as S};
+        assert_eq!(
+            info(S::Extreme, U::Expected, C::Likely).notif_urgency(),
+            Urgency::Critical
+        );
+        assert_eq!(
+            info(S::Extreme, U::Future, C::Likely).notif_urgency(),
+            Urgency::Normal
+        );
+        assert_eq!(
+            info(S::Moderate, U::Immediate, C::Observed).notif_urgency(),
+            Urgency::Normal
+        );
+        assert_eq!(
+            info(S::Severe, U::Immediate, C::Unlikely).notif_urgency(),
+            Urgency::Low
+        );
+        assert_eq!(
+            info(S::Minor, U::Immediate, C::Observed).notif_urgency(),
+            Urgency::Low
+        );
+        assert_eq!(
+            info(S::Severe, U::Past, C::Observed).notif_urgency(),
+            Urgency::Low
+        );
+    }
+
+    #[test]
+    fn reject_missing_fields() {
+        assert!(
+            Alert::parse(&ALERT.replace("<identifier>KSTO1055887203</identifier>", "")).is_err()
+        );
+        assert!(Alert::parse("<feed/>").is_err());
+    }
+}
@@ -0,0 +1,540 @@
+//! Where CAP alerts come from.
+//!
+//! Alerts are read from CAP files dropped in a local directory, and from an Atom feed (as served
+//! by e.g. FEMA IPAWS or national weather services), configured in the `[cap]` table:
+//!
+//! ```toml
+//! [cap]
+//! directory = "/var/lib/shizuku/cap"
+//! feed = "https://alerts.example.org/cap.atom"
+//! poll_interval = 300
+//! ```
+//!
+//! Alerts are deduplicated by identifier, so the same alert coming from both sources, or from
+//! several polls of the feed, is only shown once. The sources are restarted whenever the `[cap]`
+//! table changes.
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use color_eyre::Result;
+use notify::Watcher;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use super::{Alert, MsgType};
+use crate::{dbus::CloseReason, NotifStackEvent};
+
+/// Timeout of a single HTTP request.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    /// Alerts already delivered, by identifier.
+    static ref SEEN: Mutex<HashMap<String, Seen>> = Mutex::new(HashMap::new());
+    /// Links from the last poll of the feed that have already been fetched.
+    static ref FETCHED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    /// The running sources, see [start].
+    static ref SOURCES: Mutex<Option<Sources>> = Mutex::new(None);
+}
+
+/// Alert sources started from a [CapConfig].
+struct Sources {
+    config: CapConfig,
+    /// Stops watching the directory when dropped.
+    _watcher: Option<notify::RecommendedWatcher>,
+    poller: Option<async_std::task::JoinHandle<()>>,
+}
+
+/// An alert already delivered. Once off screen, it is kept in the [state](crate::state) so that
+/// it isn't shown again after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Seen {
+    /// Notif the alert is shown in, [None] for alerts that were not shown.
+    notif_id: Option<u32>,
+    expires: Option<DateTime<FixedOffset>>,
+}
+
+const fn default_poll_interval() -> u64 {
+    300
+}
+
+const fn default_actual_only() -> bool {
+    true
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct CapConfig {
+    /// Directory watched for `*.xml` CAP files.
+    pub directory: Option<PathBuf>,
+    /// URL of an Atom feed of CAP alerts.
+    pub feed: Option<String>,
+    /// Secs between two polls of the feed.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Ignore alerts whose status isn't `Actual`, e.g. exercises and tests.
+    #[serde(default = "default_actual_only")]
+    pub actual_only: bool,
+}
+
+impl Default for CapConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            feed: None,
+            poll_interval: default_poll_interval(),
+            actual_only: default_actual_only(),
+        }
+    }
+}
+
+/// The alerts delivered so far that haven't expired, by identifier.
+///
+/// Alerts whose notif is still `on_screen` are left out: the user hasn't seen them through, so
+/// they are shown again when their source delivers them after a restart.
+pub fn seen(on_screen: impl Fn(u32) -> bool) -> HashMap<String, Seen> {
+    let now = Utc::now();
+    let mut seen = SEEN.lock().unwrap();
+    seen.retain(|_, s| s.expires.is_none_or(|expires| expires > now));
+    (seen.iter())
+        .filter(|(_, s)| !s.notif_id.is_some_and(&on_screen))
+        .map(|(identifier, s)| (identifier.clone(), s.clone()))
+        .collect()
+}
+
+/// Restores the alerts delivered before a restart, before any source is started.
+pub fn restore_seen(restored: HashMap<String, Seen>) {
+    SEEN.lock().unwrap().extend(restored);
+}
+
+/// Shows an alert, or closes the alerts it cancels.
+#[tracing::instrument(skip(alert), fields(identifier = alert.identifier))]
+pub fn deliver(alert: Alert) {
+    let actual_only = crate::config::get().cap.actual_only;
+    if actual_only && alert.status != "Actual" {
+        return debug!(status = alert.status, "Ignoring alert that isn't actual");
+    }
+
+    let now = Utc::now();
+    let mut seen = SEEN.lock().unwrap();
+    seen.retain(|_, s| s.expires.is_none_or(|expires| expires > now));
+    if seen.contains_key(&alert.identifier) {
+        return debug!("Ignoring duplicate alert");
+    }
+
+    if matches!(alert.msg_type, MsgType::Update | MsgType::Cancel) {
+        for reference in &alert.references {
+            if let Some(id) = seen.get(reference).and_then(|s| s.notif_id) {
+                debug!(reference, "Closing superseded alert");
+                crate::send_event(NotifStackEvent::Closed(id, CloseReason::Closed));
+            }
+        }
+    }
+
+    let expires = alert.expires();
+    let show = matches!(alert.msg_type, MsgType::Alert | MsgType::Update)
+        && expires.is_none_or(|expires| expires > now);
+    let notif_id = show.then(crate::dbus::get_notification_id);
+    seen.insert(alert.identifier.clone(), Seen { notif_id, expires });
+    drop(seen);
+
+    let Some(id) = notif_id else {
+        return debug!(msg_type = ?alert.msg_type, "Not showing alert");
+    };
+    match alert.to_notification(id) {
+        Ok(notif) => {
+            info!(?notif, "Received CAP alert");
+            crate::hook::run_matching(&notif);
+            crate::send_event(NotifStackEvent::Added(notif));
+        }
+        Err(e) => error!(?e, "Cannot show alert"),
+    }
+}
+
+fn deliver_file(path: &Path) {
+    if path.extension().is_none_or(|ext| ext != "xml") {
+        return;
+    }
+    match std::fs::read_to_string(path)
+        .map_err(Into::into)
+        .and_then(|xml| Alert::parse(&xml))
+    {
+        Ok(alert) => deliver(alert),
+        Err(e) => warn!(?e, ?path, "Cannot read CAP file"),
+    }
+}
+
+/// Delivers the CAP files already in `dir`, then watches it for new ones.
+///
+/// The returned watcher must be kept alive.
+pub fn watch_dir(dir: &Path) -> notify::Result<notify::RecommendedWatcher> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .for_each(|entry| deliver_file(&entry.path())),
+        Err(e) => warn!(?e, ?dir, "Cannot read CAP directory"),
+    }
+
+    let mut watcher =
+        notify::recommended_watcher(|res: notify::Result<notify::Event>| match res {
+            Ok(event)
+                if matches!(
+                    event.kind,
+                    notify::EventKind::Create(_)
+                        | notify::EventKind::Access(notify::event::AccessKind::Close(
+                            notify::event::AccessMode::Write
+                        ))
+                ) =>
+            {
+                event.paths.iter().for_each(|path| deliver_file(path));
+            }
+            Ok(_) => {}
+            Err(e) => warn!(?e, "CAP directory watcher error"),
+        })?;
+    watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+/// An entry of an Atom feed of alerts.
+#[derive(Debug)]
+pub enum FeedEntry {
+    /// The alert is embedded in the entry.
+    Inline(Box<Alert>),
+    /// The entry links to the CAP document.
+    Link(String),
+}
+
+/// Parses an Atom feed of CAP alerts.
+pub fn parse_feed(xml: &str) -> Result<Vec<FeedEntry>> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let entries = (doc.root_element().children())
+        .filter(|n| n.tag_name().name() == "entry")
+        .filter_map(|entry| {
+            if let Some(alert) = entry.descendants().find(|n| n.tag_name().name() == "alert") {
+                return (Alert::from_node(alert))
+                    .map_err(|e| warn!(?e, "Invalid alert in feed"))
+                    .ok()
+                    .map(|alert| FeedEntry::Inline(Box::new(alert)));
+            }
+            let links = || entry.children().filter(|n| n.tag_name().name() == "link");
+            // prefer links that say they are CAP, then the alternate link
+            (links().find(|l| l.attribute("type").is_some_and(|t| t.contains("cap"))))
+                .or_else(|| links().find(|l| l.attribute("rel").is_none_or(|r| r == "alternate")))
+                .and_then(|l| l.attribute("href"))
+                .map(|href| FeedEntry::Link(href.to_string()))
+        })
+        .collect();
+    Ok(entries)
+}
+
+/// Fetches a document over HTTP. This blocks.
+pub fn fetch(url: &str) -> Result<String> {
+    Ok(ureq::get(url).timeout(HTTP_TIMEOUT).call()?.into_string()?)
+}
+
+/// Resolves a link found in the feed at `base`, links may be relative to the feed.
+pub fn resolve(base: &str, link: &str) -> Result<String> {
+    Ok(url::Url::parse(base)?.join(link)?.into())
+}
+
+/// Fetches the feed and delivers the alerts in it.
+#[tracing::instrument]
+pub async fn poll_feed(url: String) -> Result<()> {
+    let feed = {
+        let url = url.clone();
+        async_std::task::spawn_blocking(move || fetch(&url)).await?
+    };
+    let entries = parse_feed(&feed)?;
+    let links: HashSet<String> = (entries.iter())
+        .filter_map(|entry| match entry {
+            FeedEntry::Link(link) => (resolve(&url, link))
+                .map_err(|e| warn!(?e, link, "Invalid link in feed"))
+                .ok(),
+            FeedEntry::Inline(_) => None,
+        })
+        .collect();
+    // links that left the feed won't come back, forget them
+    FETCHED.lock().unwrap().retain(|link| links.contains(link));
+
+    for entry in entries {
+        match entry {
+            FeedEntry::Inline(alert) => deliver(*alert),
+            FeedEntry::Link(link) => {
+                let Ok(link) = resolve(&url, &link) else {
+                    continue;
+                };
+                if !FETCHED.lock().unwrap().insert(link.clone()) {
+                    continue;
+                }
+                let xml = async_std::task::spawn_blocking({
+                    let link = link.clone();
+                    move || fetch(&link)
+                })
+                .await;
+                match xml.and_then(|xml| Alert::parse(&xml)) {
+                    Ok(alert) => deliver(alert),
+                    Err(e) => {
+                        warn!(?e, link, "Cannot fetch alert");
+                        // try again on the next poll
+                        FETCHED.lock().unwrap().remove(&link);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Starts the configured alert sources, or restarts them if the `[cap]` table changed since they
+/// were started.
+pub fn start() {
+    let config = crate::config::get().cap.clone();
+    let mut sources = SOURCES.lock().unwrap();
+    if sources
+        .as_ref()
+        .is_some_and(|sources| sources.config == config)
+    {
+        return;
+    }
+    if let Some(Sources { poller, .. }) = sources.take() {
+        info!("CAP config changed, restarting alert sources");
+        if let Some(poller) = poller {
+            async_std::task::spawn(poller.cancel());
+        }
+    }
+
+    let poller = config.feed.clone().map(|url| {
+        let interval = Duration::from_secs(config.poll_interval.max(1));
+        async_std::task::spawn(async move {
+            loop {
+                if let Err(e) = poll_feed(url.clone()).await {
+                    warn!(?e, url, "Cannot poll CAP feed");
+                }
+                async_std::task::sleep(interval).await;
+            }
+        })
+    });
+    let watcher = config.directory.as_deref().and_then(|dir| {
+        (watch_dir(dir))
+            .map_err(|e| error!(?e, ?dir, "Cannot watch CAP directory"))
+            .ok()
+    });
+    *sources = Some(Sources {
+        config,
+        _watcher: watcher,
+        poller,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::cap::tests::ALERT;
+
+    /// Serves `routes` (path, body) over HTTP on localhost, returns the base URL.
+    fn serve(routes: Vec<(&'static str, String)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = String::new();
+                BufReader::new(&stream).read_line(&mut request).unwrap();
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let response = match routes.iter().find(|(p, _)| *p == path) {
+                    Some((_, body)) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    ),
+                    None => {
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            .to_string()
+                    }
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    fn feed(entries: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>urn:test</id>
+  <title>Alerts</title>
+  {entries}
+</feed>"#
+        )
+    }
+
+    #[test]
+    fn parse_feed_entries() {
+        let alert = ALERT.trim_start_matches(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        let xml = feed(&format!(
+            r#"<entry><id>1</id><content type="text/xml">{alert}</content></entry>
+            <entry><id>2</id><link rel="alternate" href="https://example.org/2.html"/><link type="application/cap+xml" href="https://example.org/2.cap"/></entry>
+            <entry><id>3</id><link href="https://example.org/3.cap"/></entry>
+            <entry><id>4</id></entry>"#
+        ));
+        let entries = parse_feed(&xml).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(
+            matches!(&entries[0], FeedEntry::Inline(alert) if alert.identifier == "KSTO1055887203")
+        );
+        assert!(
+            matches!(&entries[1], FeedEntry::Link(link) if link == "https://example.org/2.cap")
+        );
+        assert!(
+            matches!(&entries[2], FeedEntry::Link(link) if link == "https://example.org/3.cap")
+        );
+    }
+
+    #[test]
+    fn fetch_from_local_feed() {
+        let alert = ALERT.replace("KSTO1055887203", "fetch-test");
+        let url = serve(vec![
+            (
+                "/feed",
+                feed(
+                    r#"<entry><id>1</id><link type="application/cap+xml" href="/alert.xml"/></entry>"#,
+                ),
+            ),
+            ("/alert.xml", alert.clone()),
+        ]);
+
+        let entries = parse_feed(&fetch(&format!("{url}/feed")).unwrap()).unwrap();
+        let [FeedEntry::Link(link)] = &entries[..] else {
+            panic!("unexpected entries {entries:?}")
+        };
+        let link = resolve(&format!("{url}/feed"), link).unwrap();
+        assert_eq!(link, format!("{url}/alert.xml"));
+        let fetched = Alert::parse(&fetch(&link).unwrap()).unwrap();
+        assert_eq!(fetched.identifier, "fetch-test");
+
+        assert!(fetch(&format!("{url}/missing")).is_err());
+    }
+
+    #[test]
+    fn resolve_relative_links() {
+        let base = "https://example.org/alerts/feed.atom";
+        assert_eq!(
+            resolve(base, "1.cap").unwrap(),
+            "https://example.org/alerts/1.cap"
+        );
+        assert_eq!(
+            resolve(base, "/cap/2.xml").unwrap(),
+            "https://example.org/cap/2.xml"
+        );
+        assert_eq!(
+            resolve(base, "https://other.example.org/3.cap").unwrap(),
+            "https://other.example.org/3.cap"
+        );
+    }
+
+    /// An alert of `msg_type` that expires at `expires`, referencing `references`.
+    fn alert(identifier: &str, msg_type: &str, expires: &str, references: &[&str]) -> String {
+        let references = (references.iter())
+            .map(|r| format!("KSTO@NWS.NOAA.GOV,{r},2003-06-17T14:00:00-07:00"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        ALERT
+            .replace("KSTO1055887203", identifier)
+            .replace(
+                "<msgType>Alert</msgType>",
+                &format!("<msgType>{msg_type}</msgType><references>{references}</references>"),
+            )
+            .replace("2003-06-17T16:00:00-07:00", expires)
+    }
+
+    fn notif_id(identifier: &str) -> Option<u32> {
+        SEEN.lock().unwrap().get(identifier)?.notif_id
+    }
+
+    /// Events sent to the stack so far. Tests reading them hold [crate::test_lock].
+    fn sent_events() -> Vec<NotifStackEvent> {
+        std::iter::from_fn(|| crate::NOTIF_CHANS.1.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn deliver_dedupes_cancels_and_expires() {
+        let _lock = crate::test_lock();
+        const LATER: &str = "2999-01-01T00:00:00+00:00";
+        let parse = |xml: String| Alert::parse(&xml).unwrap();
+
+        deliver(parse(alert("deliver-1", "Alert", LATER, &[])));
+        let id = notif_id("deliver-1").expect("alert is shown");
+        deliver(parse(alert("deliver-1", "Alert", LATER, &[])));
+        assert_eq!(notif_id("deliver-1"), Some(id));
+
+        deliver(parse(alert("deliver-2", "Cancel", LATER, &["deliver-1"])));
+        assert!(SEEN.lock().unwrap().contains_key("deliver-2"));
+        assert_eq!(notif_id("deliver-2"), None);
+
+        deliver(parse(alert(
+            "deliver-3",
+            "Alert",
+            "2003-06-17T16:00:00-07:00",
+            &[],
+        )));
+        assert_eq!(notif_id("deliver-3"), None);
+
+        let events = sent_events();
+        let added = (events.iter())
+            .filter(|e| matches!(e, NotifStackEvent::Added(notif) if notif.id == id))
+            .count();
+        assert_eq!(added, 1, "duplicates are not shown again");
+        assert!(events.iter().any(|e| matches!(
+            e,
+            NotifStackEvent::Closed(closed, CloseReason::Closed) if *closed == id
+        )));
+    }
+
+    #[test]
+    fn seen_leaves_out_alerts_on_screen() {
+        let _lock = crate::test_lock();
+        const LATER: &str = "2999-01-01T00:00:00+00:00";
+        deliver(Alert::parse(&alert("seen-1", "Alert", LATER, &[])).unwrap());
+        deliver(Alert::parse(&alert("seen-2", "Alert", LATER, &[])).unwrap());
+        let on_screen = notif_id("seen-1").unwrap();
+
+        let seen = seen(|id| id == on_screen);
+        assert!(!seen.contains_key("seen-1"));
+        assert!(seen.contains_key("seen-2"));
+    }
+
+    #[test]
+    fn poll_feed_with_relative_links() {
+        let _lock = crate::test_lock();
+        let inline = alert("poll-1", "Alert", "2999-01-01T00:00:00+00:00", &[]);
+        let inline = inline.trim_start_matches(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        let url = serve(vec![
+            (
+                "/cap/feed",
+                feed(&format!(
+                    r#"<entry><id>1</id><content type="text/xml">{inline}</content></entry>
+                    <entry><id>2</id><link type="application/cap+xml" href="2.xml"/></entry>"#
+                )),
+            ),
+            (
+                "/cap/2.xml",
+                alert("poll-2", "Alert", "2999-01-01T00:00:00+00:00", &[]),
+            ),
+        ]);
+
+        async_std::task::block_on(poll_feed(format!("{url}/cap/feed"))).unwrap();
+        assert!(notif_id("poll-1").is_some());
+        assert!(notif_id("poll-2").is_some());
+        assert!(FETCHED
+            .lock()
+            .unwrap()
+            .contains(&format!("{url}/cap/2.xml")));
+    }
+}
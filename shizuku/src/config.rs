@@ -0,0 +1,485 @@
+//! User configuration.
+//!
+//! Shizuku reads `$XDG_CONFIG_HOME/shizuku/config.toml` (usually `~/.config/shizuku/config.toml`)
+//! at startup, and watches it for changes so that appearance and placement can be tweaked
+//! without restarting the daemon. Every key is optional; missing keys fall back to the defaults
+//! below, which match what used to be compiled in.
+//!
+//! ```toml
+//! corner = "top-right"
+//! width = 400
+//! max_body_lines = 3
+//! icon_size = 50
+//! idle_exit = 0
+//! style = "~/.config/shizuku/style.css"
+//!
+//! [margin]
+//! x = 15
+//! y = 30
+//! gap = 50
+//!
+//! [timeout]
+//! low = 5
+//! normal = 10
+//! critical = 0
+//!
+//! [rate_limit]
+//! burst = 10
+//! refill = 1.0
+//!
+//! [keys]
+//! dismiss = "Escape"
+//! default_action = "Return"
+//! cycle_actions = "Tab"
+//! expand = "space"
+//!
+//! # none, do_action, close, close_all, context_menu or expand
+//! [mouse]
+//! left = "expand"
+//! middle = "do_action"
+//! right = "context_menu"
+//! ```
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use gtk4_layer_shell::Edge;
+use notify::Watcher;
+use serde::Deserialize;
+use tracing::{debug, error, info, warn};
+
+use crate::dbus::Urgency;
+
+lazy_static::lazy_static! {
+    static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
+    /// See [watch]. Also remembers the stylesheet directory it watches, if not [config_dir].
+    static ref WATCHER: Mutex<Option<(notify::RecommendedWatcher, Option<PathBuf>)>> =
+        Mutex::new(None);
+}
+
+thread_local! {
+    static CSS_PROVIDER: gtk::CssProvider = gtk::CssProvider::new();
+}
+
+/// Returns the currently loaded configuration.
+pub fn get() -> std::sync::RwLockReadGuard<'static, Config> {
+    CONFIG.read().unwrap()
+}
+
+/// Screen corner that notifications stack from.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corner {
+    TopLeft,
+    #[default]
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    /// The vertical edge the stack grows away from.
+    pub const fn vertical_edge(self) -> Edge {
+        match self {
+            Self::TopLeft | Self::TopRight => Edge::Top,
+            Self::BottomLeft | Self::BottomRight => Edge::Bottom,
+        }
+    }
+
+    /// The horizontal edge toasts are pinned to.
+    pub const fn horizontal_edge(self) -> Edge {
+        match self {
+            Self::TopLeft | Self::BottomLeft => Edge::Left,
+            Self::TopRight | Self::BottomRight => Edge::Right,
+        }
+    }
+}
+
+/// Distances in pixels from the screen edges and between toasts.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Margin {
+    /// Distance from the horizontal edge of [Config::corner].
+    pub x: i32,
+    /// Distance from the vertical edge of [Config::corner].
+    pub y: i32,
+    /// Space between two stacked toasts.
+    pub gap: i32,
+}
+
+impl Default for Margin {
+    fn default() -> Self {
+        Self {
+            x: 15,
+            y: 30,
+            gap: 50,
+        }
+    }
+}
+
+/// Default time in seconds a notification stays on screen, per urgency.
+///
+/// These are used when the sender leaves the expiration to the server (`expire_timeout == -1`).
+/// `0` means the notification never expires on its own.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Timeout {
+    pub low: u64,
+    pub normal: u64,
+    pub critical: u64,
+    /// Upper bound for timeouts requested by senders, `0` to disable the cap.
+    pub max: u64,
+}
+
+impl Default for Timeout {
+    fn default() -> Self {
+        Self {
+            low: 5,
+            normal: 10,
+            critical: 0,
+            max: 10,
+        }
+    }
+}
+
+impl Timeout {
+    pub const fn for_urgency(&self, urgency: Urgency) -> u64 {
+        match urgency {
+            Urgency::Low => self.low,
+            Urgency::Normal => self.normal,
+            Urgency::Critical => self.critical,
+        }
+    }
+}
+
+/// What a key binding on a focused toast does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAction {
+    Dismiss,
+    DefaultAction,
+    CycleActions,
+    Expand,
+}
+
+/// Key bindings for a focused toast, in the format of [gtk::accelerator_parse], e.g. `<Ctrl>w`.
+///
+/// Toasts get focused by clicking them. An empty string disables the binding.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Keys {
+    /// Close the toast.
+    pub dismiss: String,
+    /// Invoke the default action, or expand the toast if it has none.
+    pub default_action: String,
+    /// Move the focus to the next action button.
+    pub cycle_actions: String,
+    /// Expand or collapse the toast.
+    pub expand: String,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Self {
+            dismiss: "Escape".to_string(),
+            default_action: "Return".to_string(),
+            cycle_actions: "Tab".to_string(),
+            expand: "space".to_string(),
+        }
+    }
+}
+
+impl Keys {
+    /// Finds the action bound to a key press.
+    ///
+    /// Must be called from the GTK main thread.
+    pub fn action_for(
+        &self,
+        key: gtk::gdk::Key,
+        state: gtk::gdk::ModifierType,
+    ) -> Option<KeyAction> {
+        let state = state & gtk::accelerator_get_default_mod_mask();
+        let matches = |binding: &str| {
+            !binding.is_empty()
+                && gtk::accelerator_parse(binding)
+                    .is_some_and(|(k, mods)| k.to_lower() == key.to_lower() && mods == state)
+        };
+        [
+            (&self.dismiss, KeyAction::Dismiss),
+            (&self.default_action, KeyAction::DefaultAction),
+            (&self.cycle_actions, KeyAction::CycleActions),
+            (&self.expand, KeyAction::Expand),
+        ]
+        .into_iter()
+        .find_map(|(binding, action)| matches(binding).then_some(action))
+    }
+}
+
+/// What a click on a toast does.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseAction {
+    #[default]
+    None,
+    /// Invoke the default action, or the first action if there is no default.
+    DoAction,
+    /// Close the toast.
+    Close,
+    /// Close every toast on screen.
+    CloseAll,
+    /// Open a menu to mute the app, snooze the notif or copy its text.
+    ContextMenu,
+    /// Expand or collapse the toast.
+    Expand,
+}
+
+/// Mouse bindings on the body of a toast.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Mouse {
+    pub left: MouseAction,
+    pub middle: MouseAction,
+    pub right: MouseAction,
+}
+
+impl Default for Mouse {
+    fn default() -> Self {
+        Self {
+            left: MouseAction::Expand,
+            middle: MouseAction::DoAction,
+            right: MouseAction::ContextMenu,
+        }
+    }
+}
+
+impl Mouse {
+    /// Finds the action bound to a mouse button, as numbered by GDK.
+    pub const fn action_for(&self, button: u32) -> MouseAction {
+        match button {
+            1 => self.left,
+            2 => self.middle,
+            3 => self.right,
+            _ => MouseAction::None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub corner: Corner,
+    pub margin: Margin,
+    /// Width of a toast in pixels.
+    pub width: i32,
+    /// Height of a collapsed toast in pixels.
+    pub height: i32,
+    /// Number of body lines shown before ellipsizing.
+    pub max_body_lines: i32,
+    /// Size in pixels of the app icon or image.
+    pub icon_size: i32,
+    pub timeout: Timeout,
+    pub keys: Keys,
+    pub mouse: Mouse,
+    /// Minutes after which the daemon exits when nothing is on screen, `0` to never exit.
+    ///
+    /// Only applies when the daemon is started through D-Bus activation, which starts it again on
+    /// the next notification. History and Do Not Disturb are kept across restarts.
+    pub idle_exit: u64,
+    /// User stylesheet. Style the toasts through the `notif-toast` CSS node.
+    pub style: Option<PathBuf>,
+    /// Emergency alert sources, see [crate::cap::source].
+    pub cap: crate::cap::source::CapConfig,
+    /// Hiding contents while the session is locked, see [crate::lock].
+    pub lock: crate::lock::LockConfig,
+    /// Journal audit trail, see [crate::audit].
+    pub audit: crate::audit::AuditConfig,
+    /// Flood protection, see [crate::ratelimit].
+    pub rate_limit: crate::ratelimit::RateLimit,
+    /// Commands to run when a notification matches, see [crate::hook].
+    #[serde(rename = "hook")]
+    pub hooks: Vec<crate::hook::Hook>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            corner: Corner::default(),
+            margin: Margin::default(),
+            width: 400,
+            height: 100,
+            max_body_lines: 3,
+            icon_size: 50,
+            timeout: Timeout::default(),
+            keys: Keys::default(),
+            mouse: Mouse::default(),
+            idle_exit: 0,
+            style: None,
+            cap: crate::cap::source::CapConfig::default(),
+            lock: crate::lock::LockConfig::default(),
+            audit: crate::audit::AuditConfig::default(),
+            rate_limit: crate::ratelimit::RateLimit::default(),
+            hooks: Vec::new(),
+        }
+    }
+}
+
+/// Expands a leading `~/` to the home directory.
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+pub fn config_dir() -> PathBuf {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default()
+        .join("shizuku")
+}
+
+pub fn config_path() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+impl Config {
+    /// Reads the configuration file, falling back to the defaults if it is missing or invalid.
+    #[tracing::instrument]
+    pub fn load(path: &Path) -> Self {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No config file, using defaults");
+                return Self::default();
+            }
+            Err(e) => {
+                warn!(?e, "Cannot read config file, using defaults");
+                return Self::default();
+            }
+        };
+        match toml::from_str::<Self>(&content) {
+            Ok(mut config) => {
+                config.style = config.style.as_deref().map(expand_home);
+                config
+            }
+            Err(e) => {
+                error!(%e, "Invalid config file, using defaults");
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Loads the configuration file into the global config.
+pub fn reload() {
+    let config = Config::load(&config_path());
+    info!(?config, "Loaded config");
+    *CONFIG.write().unwrap() = config;
+}
+
+/// Applies the user stylesheet from [Config::style] to the default display.
+///
+/// Must be called from the GTK main thread.
+pub fn apply_css() {
+    let Some(display) = gtk::gdk::Display::default() else {
+        warn!("No display to apply user CSS to");
+        return;
+    };
+    let style = get().style.clone();
+    CSS_PROVIDER.with(|provider| {
+        match style {
+            Some(path) if path.is_file() => provider.load_from_path(path),
+            Some(path) => {
+                warn!(?path, "User stylesheet not found");
+                provider.load_from_string("");
+            }
+            None => provider.load_from_string(""),
+        }
+        // adding the same provider again is a no-op
+        gtk::style_context_add_provider_for_display(
+            &display,
+            provider,
+            gtk::STYLE_PROVIDER_PRIORITY_USER,
+        );
+    });
+}
+
+/// Watches the config directory and the user stylesheet.
+///
+/// On change, the config is reloaded and [crate::NotifStackEvent::ConfigReloaded] is sent so
+/// that toasts already on screen get restyled. The config directory is created if missing, so
+/// that a config file written later is picked up too.
+pub fn watch() -> notify::Result<()> {
+    let dir = config_dir();
+    let mut watcher = notify::recommended_watcher(|res: notify::Result<notify::Event>| {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(?e, "Config watcher error");
+                return;
+            }
+        };
+        if !matches!(
+            event.kind,
+            notify::EventKind::Create(_)
+                | notify::EventKind::Modify(_)
+                | notify::EventKind::Remove(_)
+        ) {
+            return;
+        }
+        let style = get().style.clone();
+        let relevant = event
+            .paths
+            .iter()
+            .any(|p| *p == config_path() || style.as_deref() == Some(p.as_path()));
+        if !relevant {
+            return;
+        }
+        debug!(?event, "Config changed");
+        reload();
+        // the stylesheet may have moved, it is watched again from the main loop: watching from
+        // the thread of the watcher would deadlock
+        if let Err(e) = crate::NOTIF_CHANS
+            .0
+            .try_send(crate::NotifStackEvent::ConfigReloaded)
+        {
+            error!(?e, "Failed to send NotifStackEvent::ConfigReloaded");
+        }
+    })?;
+
+    // Editors usually replace files instead of writing in place, so watch the directories
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        warn!(?e, ?dir, "Cannot create config directory");
+    }
+    watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+    *WATCHER.lock().unwrap() = Some((watcher, None));
+    watch_style();
+    Ok(())
+}
+
+/// Watches the directory of the current [Config::style] instead of the previous one.
+pub fn watch_style() {
+    let mut guard = WATCHER.lock().unwrap();
+    let Some((watcher, watched)) = guard.as_mut() else {
+        return;
+    };
+    let parent = (get().style.as_deref())
+        .and_then(Path::parent)
+        .filter(|parent| *parent != config_dir())
+        .map(Path::to_path_buf);
+    if parent == *watched {
+        return;
+    }
+    if let Some(old) = watched.take() {
+        if let Err(e) = watcher.unwatch(&old) {
+            debug!(?e, ?old, "Cannot unwatch stylesheet directory");
+        }
+    }
+    if let Some(parent) = parent.filter(|parent| parent.is_dir()) {
+        match watcher.watch(&parent, notify::RecursiveMode::NonRecursive) {
+            Ok(()) => *watched = Some(parent),
+            Err(e) => warn!(?e, ?parent, "Cannot watch stylesheet directory"),
+        }
+    }
+}
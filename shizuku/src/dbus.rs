@@ -1,4 +1,10 @@
-use std::sync::RwLock;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock, RwLock,
+    },
+};
 
 use serde::{Deserialize, Serialize};
 use zbus::{dbus_interface, dbus_proxy, zvariant::Type, SignalContext};
@@ -8,16 +14,51 @@ use crate::{NotifSchedTimer, NotifStackEvent, NOTIF_CHANS};
 // An incrementing counter for notification IDs.
 lazy_static::lazy_static! {
     static ref NOTIFICATION_ID: RwLock<u32> = RwLock::new(0);
+    /// IDs handed out that weren't closed yet: the notifs on screen, snoozed or about to be shown.
+    static ref OPEN_IDS: RwLock<HashSet<u32>> = RwLock::new(HashSet::new());
 }
 
-fn get_notification_id() -> u32 {
+pub fn get_notification_id() -> u32 {
     let mut id = NOTIFICATION_ID.write().unwrap();
     *id += 1;
+    OPEN_IDS.write().unwrap().insert(*id);
     *id
 }
 
+/// Whether the notification `id` is still open, i.e. `NotificationClosed` wasn't emitted for it.
+pub fn is_open(id: u32) -> bool {
+    OPEN_IDS.read().unwrap().contains(&id)
+}
+
+/// Marks a notification restored after a restart as open again.
+pub fn reopen(id: u32) {
+    OPEN_IDS.write().unwrap().insert(id);
+}
+
+/// The last notification ID that was allocated.
+pub fn last_notification_id() -> u32 {
+    *NOTIFICATION_ID.read().unwrap()
+}
+
+/// Resumes allocating notification IDs after `id`, e.g. after a restart.
+pub fn set_last_notification_id(id: u32) {
+    *NOTIFICATION_ID.write().unwrap() = id;
+}
+
+/// Whether Do Not Disturb is on, see [crate::widget::Notification::bypasses_dnd].
+pub static DO_NOT_DISTURB: AtomicBool = AtomicBool::new(false);
+
+/// The session bus connection the server is running on.
+pub static CONNECTION: OnceLock<zbus::Connection> = OnceLock::new();
+
 pub const DBUS_OBJECT_PATH: &str = "/org/freedesktop/Notifications";
 pub const DBUS_INTERFACE: &str = "org.freedesktop.Notifications";
+/// Object path of [ShizukuServer], served alongside [NotificationsServer].
+pub const SHIZUKU_OBJECT_PATH: &str = "/com/fyralabs/Shizuku";
+/// Key of the action invoked when the notification itself is activated.
+pub const DEFAULT_ACTION: &str = "default";
+/// Key of the KDE action that asks for a text reply, answered with `NotificationReplied`.
+pub const INLINE_REPLY_ACTION: &str = "inline-reply";
 pub type NotificationHintsMap<'a> = std::collections::HashMap<&'a str, zbus::zvariant::Value<'a>>;
 
 /// D-Bus server information.
@@ -34,12 +75,14 @@ const SERVER_INFO: [&str; 4] = [
 ///
 /// - `actions`: The server will provide the specified actions to the user.
 /// - `body`: Supports body text.
-const SERVER_CAPABILITIES: [&str; 2] = ["actions", "body"];
+/// - `inline-reply`: Shows a text entry for the [INLINE_REPLY_ACTION] action (KDE extension).
+const SERVER_CAPABILITIES: [&str; 3] = ["actions", "body", "inline-reply"];
 
 // use bitflags to define the urgency level
 #[derive(
     Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Type,
 )]
+#[serde(rename_all = "lowercase")]
 /// Notification Level
 ///
 // Most notifications should be at `Normal`, but we probably want to implement different levels of urgency
@@ -66,6 +109,76 @@ impl From<u8> for Urgency {
         }
     }
 }
+/// Reason a notification was closed, sent with the `NotificationClosed` signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The notification expired.
+    Expired = 1,
+    /// The notification was dismissed by the user.
+    Dismissed = 2,
+    /// The notification was closed by a call to `CloseNotification`.
+    Closed = 3,
+    Undefined = 4,
+}
+
+/// Serves [NotificationsServer] and [ShizukuServer] on `connection`, and takes the
+/// `org.freedesktop.Notifications` name.
+///
+/// The connection is kept in [CONNECTION] to emit signals on.
+pub async fn serve(connection: zbus::Connection) -> zbus::Result<()> {
+    if CONNECTION.set(connection.clone()).is_err() {
+        tracing::warn!("Already serving on another connection");
+    }
+    let server = connection.object_server();
+    server.at(DBUS_OBJECT_PATH, NotificationsServer).await?;
+    server.at(SHIZUKU_OBJECT_PATH, ShizukuServer).await?;
+    connection.request_name(DBUS_INTERFACE).await?;
+    Ok(())
+}
+
+/// Emits `NotificationClosed` in the background.
+pub fn emit_notification_closed(id: u32, reason: CloseReason) {
+    OPEN_IDS.write().unwrap().remove(&id);
+    let Some(connection) = CONNECTION.get() else {
+        tracing::warn!(id, "No D-Bus connection to emit NotificationClosed on");
+        return;
+    };
+    async_std::task::spawn(async move {
+        let ctx = SignalContext::new(connection, DBUS_OBJECT_PATH).unwrap();
+        if let Err(e) = NotificationsServer::notification_closed(&ctx, id, reason as u32).await {
+            tracing::error!(?e, id, "Failed to emit NotificationClosed");
+        }
+    });
+}
+
+/// Emits `ActionInvoked` in the background.
+pub fn emit_action_invoked(id: u32, action_key: String) {
+    let Some(connection) = CONNECTION.get() else {
+        tracing::warn!(id, "No D-Bus connection to emit ActionInvoked on");
+        return;
+    };
+    async_std::task::spawn(async move {
+        let ctx = SignalContext::new(connection, DBUS_OBJECT_PATH).unwrap();
+        if let Err(e) = NotificationsServer::action_invoked(&ctx, id, &action_key).await {
+            tracing::error!(?e, id, "Failed to emit ActionInvoked");
+        }
+    });
+}
+
+/// Emits `NotificationReplied` in the background.
+pub fn emit_notification_replied(id: u32, text: String) {
+    let Some(connection) = CONNECTION.get() else {
+        tracing::warn!(id, "No D-Bus connection to emit NotificationReplied on");
+        return;
+    };
+    async_std::task::spawn(async move {
+        let ctx = SignalContext::new(connection, DBUS_OBJECT_PATH).unwrap();
+        if let Err(e) = NotificationsServer::notification_replied(&ctx, id, &text).await {
+            tracing::error!(?e, id, "Failed to emit NotificationReplied");
+        }
+    });
+}
+
 /// Notification Position
 // Honestly I don't know if we would need this, since it would go against Helium HIG
 // All notifications should be at a specific corner, and not move around
@@ -169,7 +282,7 @@ pub struct NotificationHints {
 ///
 /// Source: `bus/dbus.xml`.
 #[dbus_proxy(interface = "org.freedesktop.Notifications", assume_defaults = true)]
-trait Notifications {
+pub trait Notifications {
     /// CloseNotification method
     fn close_notification(&self, id: u32) -> zbus::Result<()>;
 
@@ -200,6 +313,10 @@ trait Notifications {
     /// NotificationClosed signal
     #[dbus_proxy(signal)]
     fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+
+    /// NotificationReplied signal
+    #[dbus_proxy(signal)]
+    fn notification_replied(&self, id: u32, text: &str) -> zbus::Result<()>;
 }
 
 // Let's implement a server interface based on what we have for this client proxy
@@ -224,6 +341,7 @@ impl NotificationsServer {
     /// Once a notification is closed, it is removed from the feed entirely.
     async fn close_notification(&self, id: u32) -> Result<(), zbus::fdo::Error> {
         tracing::info!(?id, "CloseNotification");
+        crate::send_event(NotifStackEvent::Closed(id, CloseReason::Closed));
         Ok(())
     }
 
@@ -256,9 +374,10 @@ impl NotificationsServer {
     /// This method gets called with a notification is sent from an application.
     /// The code below should push the notification to the GTK4 Layer Shell interface
     /// and then display the notification on the screen for the user to see.
-    #[tracing::instrument(skip(self))]
+    #[tracing::instrument(skip(self, header))]
     async fn notify(
         &self,
+        #[zbus(header)] header: zbus::MessageHeader<'_>,
         app_name: &str,
         replaces_id: u32,
         app_icon: &str,
@@ -271,52 +390,76 @@ impl NotificationsServer {
         // need a better way to serialize a{sv} to our struct with optional values?
         expire_timeout: i32,
     ) -> Result<u32, zbus::fdo::Error> {
-        // allocate a notification ID
-        let id = get_notification_id();
-
-        let urgency_int = hints.get("urgency");
+        let sender = (header.sender().ok().flatten()).map_or_else(String::new, ToString::to_string);
+        crate::ratelimit::check(&sender, app_name)?;
+
+        // allocate a notification ID, unless this replaces a notif that is still open. IDs that
+        // are unknown or closed already would collide with the IDs handed out later.
+        let id = if replaces_id != 0 && is_open(replaces_id) {
+            replaces_id
+        } else {
+            get_notification_id()
+        };
 
-        let urgency = match urgency_int {
-            Some(urgency) => {
-                let urgency = urgency.downcast_ref::<u8>().unwrap();
-                (*urgency).into()
-            }
+        let urgency = match hints.get("urgency") {
+            Some(urgency) => urgency.downcast_ref::<u8>().map_or_else(
+                || {
+                    tracing::warn!(?urgency, "Invalid urgency hint");
+                    Urgency::default()
+                },
+                |urgency| (*urgency).into(),
+            ),
             None => Urgency::default(),
         };
 
-        let image_data = hints.get("image-data").map(|image_data| {
-            tracing::debug!("{image_data:#?}");
-            crate::icon::ImageData::from(image_data)
-        });
+        let hint_str = |key: &str| {
+            (hints.get(key))
+                .and_then(|v| v.downcast_ref::<str>())
+                .map(str::to_string)
+        };
 
-        // expire_timeout is a hint that can be -1, or a positive integer
+        let image_data = hints.get("image-data").and_then(|image_data| {
+            (crate::icon::ImageData::try_from(image_data))
+                .map_err(|e| tracing::warn!(%e, "Invalid image-data hint"))
+                .ok()
+        });
 
-        // SAFETY:
-        // This `as` conversion is safe and intentional. -1 will overflow to a really large value,
-        // and that value is definitely larger than [`MAX_DURATION`] and get capped to that limit.
-        let expire_timeout = NotifSchedTimer::with_duration_secs(expire_timeout as u128);
+        // expire_timeout is -1 for the server default, 0 for never, or a timeout in milliseconds
+        let expire_timeout = NotifSchedTimer::from_expire_timeout(expire_timeout, urgency);
         tracing::trace!(
             duration = expire_timeout.duration,
             "Connected NotifSchedTimer"
         );
 
         let notif = crate::widget::Notification {
+            app_name: app_name.to_string(),
             title: summary.to_string(),
             body: body.to_string(),
             icon: Some(app_icon.to_string()),
             urgency,
+            category: hint_str("category"),
+            desktop_entry: hint_str("desktop-entry"),
+            reply_placeholder: hint_str("x-kde-reply-placeholder-text"),
+            attachments: (hints.get("x-kde-urls"))
+                .map(crate::attachment::from_hint)
+                .unwrap_or_default(),
             id,
             image_data,
+            actions: (actions.chunks_exact(2))
+                .map(|pair| (pair[0].to_string(), pair[1].to_string()))
+                .collect(),
             sched: expire_timeout,
             ..Default::default()
         };
 
         tracing::info!(?notif, "Received notification");
 
+        crate::hook::run_matching(&notif);
+
         // send the notification to the notification stack
         let _ = (NOTIF_CHANS.0.send(NotifStackEvent::Added(notif)).await)
             .map_err(|e| tracing::error!(?e, "Failed to send NotifStackEvent::Added"));
-        Ok(0)
+        Ok(id)
     }
 
     // Signals
@@ -346,4 +489,95 @@ impl NotificationsServer {
         tracing::trace!("NotificationClosed");
         Ok(())
     }
+
+    /// Emitted with the text the user typed in reply to a notif with the
+    /// [INLINE_REPLY_ACTION] action (KDE extension).
+    #[dbus_interface(signal)]
+    #[tracing::instrument]
+    async fn notification_replied(
+        ctx: &SignalContext<'_>,
+        id: u32,
+        text: &str,
+    ) -> zbus::Result<()> {
+        tracing::trace!("NotificationReplied");
+        Ok(())
+    }
+}
+
+/// D-Bus interface to control the daemon itself, e.g. from compositor key bindings.
+///
+/// It is served at [SHIZUKU_OBJECT_PATH] under the `org.freedesktop.Notifications` name:
+///
+/// ```sh
+/// gdbus call --session --dest org.freedesktop.Notifications \
+///     --object-path /com/fyralabs/Shizuku --method com.fyralabs.Shizuku.DismissAll
+/// ```
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShizukuServer;
+
+#[dbus_interface(name = "com.fyralabs.Shizuku")]
+impl ShizukuServer {
+    /// Dismisses the most recent notification on screen.
+    fn dismiss_newest(&self) {
+        tracing::info!("DismissNewest");
+        crate::send_event(NotifStackEvent::DismissNewest);
+    }
+
+    /// Dismisses every notification on screen.
+    fn dismiss_all(&self) {
+        tracing::info!("DismissAll");
+        crate::send_event(NotifStackEvent::DismissAll);
+    }
+
+    /// Shows the last closed notification again.
+    fn restore_last(&self) {
+        tracing::info!("RestoreLast");
+        crate::send_event(NotifStackEvent::RestoreLast);
+    }
+
+    /// Hides a notification for `secs` seconds, or until tomorrow morning if `secs` is 0.
+    ///
+    /// `NotificationClosed` is only emitted once the notification is shown again and closed.
+    /// Only notifications on screen can be snoozed, not ones snoozed already or in the history.
+    async fn snooze(&self, id: u32, secs: u64) -> zbus::fdo::Result<()> {
+        tracing::info!(id, secs, "Snooze");
+        let until = if secs == 0 {
+            NotifSchedTimer::tomorrow_morning()
+        } else {
+            NotifSchedTimer::in_secs(secs)
+        };
+        let (reply, snoozed) = async_std::channel::bounded(1);
+        crate::send_event(NotifStackEvent::Snoozed(id, until, Some(reply)));
+        if !matches!(snoozed.recv().await, Ok(true)) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "No notification with ID {id} on screen"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Shows notifications from an app muted from a toast again.
+    ///
+    /// `app` is the desktop entry of the app, or its name if it has none.
+    fn unmute(&self, app: String) {
+        tracing::info!(app, "Unmute");
+        crate::send_event(NotifStackEvent::Unmuted(app));
+    }
+
+    /// Do Not Disturb
+    ///
+    /// While on, notifications are not shown but go straight to the history, except for critical
+    /// notifications and emergency alerts.
+    #[dbus_interface(property)]
+    fn do_not_disturb(&self) -> bool {
+        DO_NOT_DISTURB.load(Ordering::Relaxed)
+    }
+
+    /// zbus emits `PropertiesChanged` once this returns.
+    #[dbus_interface(property)]
+    fn set_do_not_disturb(&mut self, value: bool) {
+        tracing::info!(value, "Setting DoNotDisturb");
+        DO_NOT_DISTURB.store(value, Ordering::Relaxed);
+        crate::send_event(NotifStackEvent::DoNotDisturbChanged);
+    }
 }
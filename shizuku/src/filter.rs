@@ -0,0 +1,33 @@
+//! Matching notifications against user rules.
+use serde::Deserialize;
+
+use crate::{dbus::Urgency, widget::Notification};
+
+/// Matches notifications on their app name, category and urgency.
+///
+/// Every field is optional; a field that is not set matches everything, so an empty filter
+/// matches all notifications.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default)]
+pub struct NotifFilter {
+    /// App name as sent by the application, compared case-insensitively.
+    pub app_name: Option<String>,
+    /// Category such as `im.received`. A category class like `im` matches all categories in it.
+    pub category: Option<String>,
+    pub urgency: Option<Urgency>,
+}
+
+impl NotifFilter {
+    pub fn matches(&self, notif: &Notification) -> bool {
+        let app_name = (self.app_name.as_ref())
+            .is_none_or(|app_name| app_name.eq_ignore_ascii_case(&notif.app_name));
+        let category = self.category.as_ref().is_none_or(|filter| {
+            notif.category.as_ref().is_some_and(|category| {
+                category == filter
+                    || (category.strip_prefix(filter.as_str())).is_some_and(|s| s.starts_with('.'))
+            })
+        });
+        let urgency = self.urgency.is_none_or(|urgency| urgency == notif.urgency);
+        app_name && category && urgency
+    }
+}
@@ -0,0 +1,104 @@
+//! Script hooks, run when a notification matches a rule.
+//!
+//! ```toml
+//! [[hook]]
+//! app_name = "Alertmanager"
+//! urgency = "critical"
+//! exec = "blink1-tool --red --blink 10"
+//! timeout = 5
+//! ```
+//!
+//! The command is run with `sh -c`, with the notification passed in the environment as
+//! `SHIZUKU_APP_NAME`, `SHIZUKU_SUMMARY`, `SHIZUKU_BODY`, `SHIZUKU_URGENCY`, `SHIZUKU_CATEGORY`
+//! and `SHIZUKU_ID`.
+use std::{
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tracing::{debug, error, warn};
+
+use crate::{dbus::Urgency, filter::NotifFilter, widget::Notification};
+
+/// How often to check whether a hook has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const fn default_timeout() -> u64 {
+    10
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Hook {
+    #[serde(flatten)]
+    pub filter: NotifFilter,
+    /// Shell command to run.
+    pub exec: String,
+    /// Secs after which the command gets killed.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn notif_env(notif: &Notification) -> [(&'static str, String); 6] {
+    let urgency = match notif.urgency {
+        Urgency::Low => "low",
+        Urgency::Normal => "normal",
+        Urgency::Critical => "critical",
+    };
+    [
+        ("SHIZUKU_APP_NAME", notif.app_name.clone()),
+        ("SHIZUKU_SUMMARY", notif.title.clone()),
+        ("SHIZUKU_BODY", notif.body.clone()),
+        ("SHIZUKU_URGENCY", urgency.to_string()),
+        (
+            "SHIZUKU_CATEGORY",
+            notif.category.clone().unwrap_or_default(),
+        ),
+        ("SHIZUKU_ID", notif.id.to_string()),
+    ]
+}
+
+/// Runs every hook matching `notif` in the background.
+pub fn run_matching(notif: &Notification) {
+    let hooks = (crate::config::get().hooks.iter())
+        .filter(|hook| hook.filter.matches(notif))
+        .cloned()
+        .collect::<Vec<_>>();
+    for hook in hooks {
+        let env = notif_env(notif);
+        async_std::task::spawn(run(hook, env));
+    }
+}
+
+#[tracing::instrument(skip(env))]
+async fn run(hook: Hook, env: [(&'static str, String); 6]) {
+    let mut child = match Command::new("sh")
+        .arg("-c")
+        .arg(&hook.exec)
+        .envs(env)
+        .stdin(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return error!(?e, "Cannot spawn hook"),
+    };
+
+    // don't block the executor on wait(), poll until the hook exits or times out
+    let deadline = Instant::now() + Duration::from_secs(hook.timeout);
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return debug!("Hook finished"),
+            Ok(Some(status)) => return warn!(?status, "Hook failed"),
+            Ok(None) if Instant::now() >= deadline => break,
+            Ok(None) => async_std::task::sleep(POLL_INTERVAL).await,
+            Err(e) => return error!(?e, "Cannot wait for hook"),
+        }
+    }
+
+    warn!("Hook timed out, killing it");
+    if let Err(e) = child.kill() {
+        error!(?e, "Cannot kill hook");
+    }
+    // reap the zombie
+    let _ = child.wait();
+}
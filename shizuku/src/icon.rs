@@ -1,8 +1,9 @@
+use serde::{Deserialize, Serialize};
 use zvariant::Value;
 
 // (iiibiiay)
 // width, height, rowstride, has alpha, bits per sample, channels, image data
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[allow(dead_code)]
 // channel data is unused for now
 pub struct ImageData(i32, i32, i32, bool, i32, i32, Vec<u8>);
@@ -21,28 +22,51 @@ impl From<&ImageData> for gtk::gdk_pixbuf::Pixbuf {
     }
 }
 
-impl From<&zvariant::Value<'_>> for ImageData {
-    fn from(value: &zvariant::Value<'_>) -> Self {
+impl TryFrom<&zvariant::Value<'_>> for ImageData {
+    type Error = zvariant::Error;
+
+    /// Reads an `image-data` hint, checking that the pixels fit the given dimensions so that
+    /// gdk-pixbuf never reads out of bounds.
+    fn try_from(value: &zvariant::Value<'_>) -> Result<Self, Self::Error> {
         let Value::Structure(data) = value else {
-            unreachable!()
+            return Err(zvariant::Error::IncorrectType);
         };
         let [Value::I32(w), Value::I32(h), Value::I32(r), Value::Bool(a), Value::I32(s), Value::I32(c), Value::Array(d)] =
             &data.fields()[..]
         else {
-            unreachable!()
+            return Err(zvariant::Error::IncorrectType);
         };
-        let d = d.iter().map(|v| {
-            let Value::U8(v) = v else { unreachable!() };
-            *v
-        });
-        Self {
+        let d = (d.iter())
+            .map(|v| match v {
+                Value::U8(v) => Ok(*v),
+                _ => Err(zvariant::Error::IncorrectType),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let channels = if *a { 4 } else { 3 };
+        let fits = (usize::try_from(*r).ok())
+            .zip(usize::try_from(*h).ok())
+            .is_some_and(|(r, h)| r.checked_mul(h).is_some_and(|len| len <= d.len()));
+        if *w <= 0
+            || *h <= 0
+            || *s != 8
+            || *c != channels
+            || i64::from(*r) < i64::from(*w) * i64::from(*c)
+            || !fits
+        {
+            return Err(zvariant::Error::Message(format!(
+                "invalid image of {w}x{h}, rowstride {r}, {c} channels of {s} bits, {} bytes",
+                d.len()
+            )));
+        }
+        Ok(Self {
             0: *w,
             1: *h,
             2: *r,
             3: *a,
             4: *s,
             5: *c,
-            6: d.collect(),
-        }
+            6: d,
+        })
     }
 }
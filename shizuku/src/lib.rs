@@ -0,0 +1,383 @@
+//! Shizuku, the notification daemon of the KIRI Desktop Environment.
+//!
+//! The `shizukud` binary runs [Application] and serves [dbus::NotificationsServer] with
+//! [dbus::serve].
+pub mod attachment;
+pub mod audit;
+pub mod cap;
+pub mod config;
+pub mod dbus;
+pub mod filter;
+pub mod hook;
+pub mod icon;
+pub mod lock;
+pub mod ratelimit;
+pub mod stack;
+pub mod state;
+pub mod widget;
+
+use gio::prelude::{ApplicationExt, ApplicationExtManual};
+
+use stack::NotificationStack;
+use tracing::{debug, error};
+
+pub const APPLICATION_ID: &str = "com.fyralabs.shizuku";
+/// Passed by the D-Bus and systemd service files, see [Application::bus_activated].
+pub const ACTIVATED_FLAG: &str = "--activated";
+lazy_static::lazy_static! {
+    static ref NOTIF_CHANS: std::sync::Arc<(async_std::channel::Sender<NotifStackEvent>, async_std::channel::Receiver<NotifStackEvent>)>
+        = std::sync::Arc::new(async_std::channel::unbounded());
+}
+
+/// Serializes the unit tests that go through global state, e.g. [NOTIF_CHANS], so that they
+/// don't see each other's events.
+#[cfg(test)]
+fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    // a failed test must not fail the others
+    LOCK.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn time_now() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards nya??")
+        .as_millis()
+}
+
+#[derive(Default, Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct NotifSchedTimer {
+    pub until: u128,      // scheduled unix time in ms to hide the notif
+    pub duration: u128,   // duration of notif on screen in secs
+    paused: Option<u128>, // ms left on the timer when it was paused
+}
+
+impl NotifSchedTimer {
+    pub fn new() -> Self {
+        Self::from_expire_timeout(-1, dbus::Urgency::default())
+    }
+
+    /// Creates a timer from the `expire_timeout` argument of a `Notify` call.
+    ///
+    /// `-1` uses the configured default for `urgency`, `0` never expires, and anything else is a
+    /// timeout in milliseconds as per the spec.
+    pub fn from_expire_timeout(expire_timeout: i32, urgency: dbus::Urgency) -> Self {
+        let secs = match expire_timeout {
+            ..=-1 => config::get().timeout.for_urgency(urgency),
+            0 => 0,
+            #[allow(clippy::cast_sign_loss)]
+            ms => (ms as u64).div_ceil(1000),
+        };
+        Self::with_duration_secs(u128::from(secs))
+    }
+
+    /// Creates a timer that expires in `duration` secs, capped to [config::Timeout::max].
+    ///
+    /// A `duration` of 0 never expires.
+    pub fn with_duration_secs(duration: u128) -> Self {
+        if duration == 0 {
+            return Self::persistent();
+        }
+        let max = u128::from(config::get().timeout.max);
+        let duration = if max == 0 {
+            duration
+        } else {
+            duration.min(max)
+        };
+        Self {
+            until: time_now() + duration * 1000,
+            duration,
+            paused: None,
+        }
+    }
+
+    /// A timer that expires at `until`, in unix time in ms.
+    pub fn until(until: u128) -> Self {
+        Self {
+            until,
+            duration: until.saturating_sub(time_now()) / 1000,
+            paused: None,
+        }
+    }
+
+    /// A timer that expires in `secs` secs, regardless of [config::Timeout::max].
+    pub fn in_secs(secs: u64) -> Self {
+        Self::until(time_now() + u128::from(secs) * 1000)
+    }
+
+    /// A timer that expires tomorrow morning, at 9:00 local time.
+    pub fn tomorrow_morning() -> Self {
+        let tomorrow = (chrono::Local::now().date_naive().succ_opt())
+            .and_then(|day| day.and_hms_opt(9, 0, 0))
+            .and_then(|time| time.and_local_timezone(chrono::Local).earliest())
+            .and_then(|time| u128::try_from(time.timestamp_millis()).ok());
+        tomorrow.map_or_else(|| Self::in_secs(24 * 60 * 60), Self::until)
+    }
+
+    /// A timer that never expires.
+    pub const fn persistent() -> Self {
+        Self {
+            until: u128::MAX,
+            duration: 0,
+            paused: None,
+        }
+    }
+
+    /// Stops the timer until [NotifSchedTimer::resume] is called.
+    pub fn pause(&mut self) {
+        if self.paused.is_some() || self.until == u128::MAX {
+            return;
+        }
+        self.paused = Some(self.until.saturating_sub(time_now()));
+        self.until = u128::MAX;
+    }
+
+    /// Restarts a paused timer with the time that was left on it.
+    pub fn resume(&mut self) {
+        if let Some(left) = self.paused.take() {
+            self.until = time_now() + left;
+        }
+    }
+
+    #[inline]
+    pub fn is_over(&self) -> bool {
+        time_now() >= self.until
+    }
+}
+
+/// Sends an event to the [NotificationStack].
+pub fn send_event(event: NotifStackEvent) {
+    if let Err(e) = NOTIF_CHANS.0.try_send(event) {
+        error!(?e, "Failed to send NotifStackEvent");
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum NotifStackEvent {
+    Closed(u32, dbus::CloseReason), // notif id
+    Added(widget::Notification),
+    /// Close every notif in the group with this key.
+    GroupClosed(String),
+    /// Expand or collapse the group with this key.
+    GroupToggled(String),
+    /// Expand or collapse the body of the notif with this id.
+    ExpandToggled(u32),
+    /// An action of a notif was invoked: notif id, action key.
+    ActionInvoked(u32, String),
+    /// The user sent an inline reply to a notif: notif id, text.
+    Replied(u32, String),
+    /// The reply entry of a notif gained or lost the focus.
+    ReplyFocused(u32, bool),
+    /// Hide a notif until the timer is over. The sender, if any, is told whether the notif was
+    /// on screen.
+    Snoozed(
+        u32,
+        NotifSchedTimer,
+        Option<async_std::channel::Sender<bool>>,
+    ),
+    /// Stop showing notifs from the group with this key.
+    Muted(String),
+    /// Show notifs from the group with this key again.
+    Unmuted(String),
+    /// Do Not Disturb was turned on or off, see [dbus::DO_NOT_DISTURB].
+    DoNotDisturbChanged,
+    DismissNewest,
+    DismissAll,
+    /// Show the last closed notif again.
+    RestoreLast,
+    /// The config file or user stylesheet changed and has been reloaded.
+    ConfigReloaded,
+    /// The session got locked or unlocked, see [lock].
+    LockChanged,
+    /// Save the state and quit, e.g. on SIGTERM.
+    Quit,
+}
+
+#[derive(Clone)]
+pub struct Application {
+    pub app: libhelium::Application,
+    pub stack: NotificationStack,
+    /// Whether the daemon was started through D-Bus activation, which starts it again on the
+    /// next notification. Only then does it exit on idle.
+    pub bus_activated: bool,
+}
+
+impl Default for Application {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Application {
+    pub fn new() -> Self {
+        let app = libhelium::Application::builder()
+            .application_id(APPLICATION_ID)
+            .flags(gio::ApplicationFlags::NON_UNIQUE)
+            .build();
+
+        let state = state::State::load();
+        dbus::DO_NOT_DISTURB.store(state.do_not_disturb, std::sync::atomic::Ordering::Relaxed);
+        dbus::set_last_notification_id(state.last_id);
+        cap::source::restore_seen(state.cap_alerts);
+        let stack = NotificationStack::restored(state.history, state.snoozed, state.muted);
+
+        Self {
+            app,
+            stack,
+            bus_activated: false,
+        }
+    }
+
+    fn activated(_: &libhelium::Application) {
+        tracing::info!("Application activated")
+    }
+
+    fn started(_: &libhelium::Application) {
+        config::apply_css();
+    }
+
+    pub fn run(&mut self) -> gtk::glib::ExitCode {
+        let mut self_clone = self.clone();
+        gtk::glib::MainContext::default().spawn_local(async move {
+            self_clone.poll_msg_queue().await;
+        });
+        self.app.connect_startup(Self::started);
+        self.app.connect_activate(Self::activated);
+        // systemd stops the daemon with SIGTERM, save the state first
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            gtk::glib::unix_signal_add_local(signal, || {
+                send_event(NotifStackEvent::Quit);
+                gtk::glib::ControlFlow::Continue
+            });
+        }
+        let _ = self.app.hold();
+        // GTK would reject the flag as an unknown option
+        let args = std::env::args()
+            .filter(|arg| arg != ACTIVATED_FLAG)
+            .collect::<Vec<_>>();
+        self.app.run_with_args(&args)
+    }
+
+    /// Saves the [state::State].
+    #[tracing::instrument(skip(self))]
+    fn save_state(&self) {
+        let state = state::State {
+            do_not_disturb: dbus::DO_NOT_DISTURB.load(std::sync::atomic::Ordering::Relaxed),
+            last_id: dbus::last_notification_id(),
+            history: (self.stack.history().cloned())
+                .map(|notif| widget::Notification {
+                    image_data: None,
+                    ..notif
+                })
+                .collect(),
+            snoozed: self.stack.snoozed().to_vec(),
+            muted: self.stack.muted().clone(),
+            cap_alerts: cap::source::seen(|id| self.stack.get(id).is_some()),
+        };
+        if let Err(e) = state.save() {
+            error!(?e, "Failed to save state");
+        }
+    }
+
+    /// Saves the [state::State] and quits.
+    fn quit(&self) {
+        self.save_state();
+        self.app.quit();
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn poll_msg_queue(&mut self) {
+        debug!("Polling the message queue for events");
+        let rx = &NOTIF_CHANS.1;
+        let mut idle_since = None;
+
+        loop {
+            // we don't want CPU 100% usage
+            async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+            if !self.stack.is_empty() {
+                self.stack.poll();
+            }
+            if self.stack.wake_snoozed(&self.app) {
+                self.save_state();
+            }
+
+            let idle_exit = config::get().idle_exit;
+            if self.bus_activated && idle_exit > 0 && self.stack.is_idle() {
+                let since = *idle_since.get_or_insert_with(std::time::Instant::now);
+                if since.elapsed().as_secs() >= idle_exit * 60 {
+                    tracing::info!(idle_exit, "Exiting on idle");
+                    return self.quit();
+                }
+            } else {
+                idle_since = None;
+            }
+
+            let Ok(event) = rx.try_recv() else {
+                if rx.is_closed() {
+                    panic!("NOTIF_CHANS are closed");
+                }
+                continue; // rx.is_empty()
+            };
+            debug!(?event, "Processing event");
+
+            match event {
+                NotifStackEvent::Closed(index, reason) => {
+                    debug!(?index, "Removing notif because received close event");
+                    self.stack.remove(index, reason);
+                }
+                NotifStackEvent::GroupClosed(key) => {
+                    self.stack.remove_group(&key, dbus::CloseReason::Dismissed);
+                }
+                NotifStackEvent::GroupToggled(key) => self.stack.toggle_group(&key),
+                NotifStackEvent::ExpandToggled(id) => self.stack.toggle_expanded(id),
+                NotifStackEvent::ActionInvoked(id, key) => self.stack.invoke_action(id, key),
+                NotifStackEvent::Replied(id, text) => self.stack.reply(id, text),
+                NotifStackEvent::ReplyFocused(id, focused) => {
+                    self.stack.set_replying(id, focused);
+                }
+                NotifStackEvent::Snoozed(id, until, reply) => {
+                    let snoozed = self.stack.snooze(id, until);
+                    if let Some(reply) = reply {
+                        _ = reply.try_send(snoozed);
+                    }
+                    // snoozes must survive the daemon being restarted
+                    if snoozed {
+                        self.save_state();
+                    }
+                }
+                NotifStackEvent::Muted(key) => {
+                    self.stack.mute(key);
+                    self.save_state();
+                }
+                NotifStackEvent::Unmuted(key) => {
+                    self.stack.unmute(&key);
+                    self.save_state();
+                }
+                NotifStackEvent::DoNotDisturbChanged => self.save_state(),
+                NotifStackEvent::DismissNewest => self.stack.dismiss_newest(),
+                NotifStackEvent::DismissAll => self.stack.dismiss_all(),
+                NotifStackEvent::RestoreLast => self.stack.restore_last(&self.app),
+                NotifStackEvent::Added(notif) => {
+                    // what became of the notif is only known once added, keep it for the audit
+                    let audited = audit::is_enabled().then(|| notif.clone());
+                    let added = self.stack.add(notif, &self.app);
+                    if let Some(notif) = audited {
+                        audit::delivered(&notif, added);
+                    }
+                }
+                NotifStackEvent::ConfigReloaded => {
+                    config::watch_style();
+                    config::apply_css();
+                    cap::source::start();
+                    self.stack.restyle();
+                }
+                NotifStackEvent::LockChanged => self.stack.restyle(),
+                NotifStackEvent::Quit => {
+                    tracing::info!("Quitting");
+                    return self.quit();
+                }
+            }
+        }
+    }
+}
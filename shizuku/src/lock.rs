@@ -0,0 +1,267 @@
+//! Hiding notification contents while the session is locked.
+//!
+//! The lock state comes from the `LockedHint` property of the logind session, on the system bus.
+//! While it is set, toasts only show the app name, unless a rule in the `[lock]` table says
+//! otherwise:
+//!
+//! ```toml
+//! [lock]
+//! hide_content = true
+//!
+//! [[lock.show]]
+//! app_name = "Clocks"
+//!
+//! [[lock.show]]
+//! category = "device"
+//! ```
+//!
+//! Toasts are rebuilt when the session is unlocked, so notifications that came in meanwhile can
+//! be read in full. Emergency alerts are never hidden.
+use std::{
+    os::unix::fs::MetadataExt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use async_std::stream::StreamExt;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+use zbus::{dbus_proxy, zvariant::OwnedObjectPath};
+
+use crate::{filter::NotifFilter, widget::Notification, NotifStackEvent};
+
+/// Whether the session is locked.
+static LOCKED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct LockConfig {
+    /// Hide the summary, body and images of notifications while locked.
+    pub hide_content: bool,
+    /// Notifications shown in full even while locked.
+    pub show: Vec<NotifFilter>,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            hide_content: true,
+            show: Vec::new(),
+        }
+    }
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait LoginManager {
+    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
+
+    fn get_user(&self, uid: u32) -> zbus::Result<OwnedObjectPath>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.User",
+    default_service = "org.freedesktop.login1"
+)]
+trait LoginUser {
+    /// The graphical session of the user.
+    #[dbus_proxy(property)]
+    fn display(&self) -> zbus::Result<(String, OwnedObjectPath)>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1"
+)]
+trait LoginSession {
+    #[dbus_proxy(property)]
+    fn locked_hint(&self) -> zbus::Result<bool>;
+}
+
+pub fn is_locked() -> bool {
+    LOCKED.load(Ordering::Relaxed)
+}
+
+/// Whether the contents of `notif` must be hidden right now.
+///
+/// This reads the config, don't call it while holding [crate::config::get].
+pub fn hides(notif: &Notification) -> bool {
+    if !is_locked() || notif.category.as_deref() == Some(crate::cap::CATEGORY) {
+        return false;
+    }
+    let config = crate::config::get();
+    config.lock.hide_content && !config.lock.show.iter().any(|filter| filter.matches(notif))
+}
+
+/// Finds the logind session the daemon belongs to.
+///
+/// The daemon may run as a systemd user service, outside of any session, so this falls back to
+/// the graphical session of the user.
+async fn find_session(
+    connection: &zbus::Connection,
+    manager: &LoginManagerProxy<'_>,
+) -> zbus::Result<OwnedObjectPath> {
+    let id = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".to_string());
+    match manager.get_session(&id).await {
+        Ok(path) => return Ok(path),
+        Err(e) => debug!(?e, id, "No session for the daemon, using the user display"),
+    }
+    let uid = std::fs::metadata("/proc/self")?.uid();
+    let user = LoginUserProxy::builder(connection)
+        .path(manager.get_user(uid).await?)?
+        .build()
+        .await?;
+    Ok(user.display().await?.1)
+}
+
+fn set_locked(locked: bool) {
+    if LOCKED.swap(locked, Ordering::Relaxed) != locked {
+        info!(locked, "Session lock changed");
+        crate::send_event(NotifStackEvent::LockChanged);
+    }
+}
+
+/// Follows the `LockedHint` of the session on the logind at `connection`, usually the system bus.
+///
+/// This only returns on errors, or when logind goes away.
+#[tracing::instrument(skip(connection))]
+pub async fn watch(connection: zbus::Connection) -> zbus::Result<()> {
+    let manager = LoginManagerProxy::new(&connection).await?;
+    let path = find_session(&connection, &manager).await?;
+    debug!(?path, "Watching session lock");
+    let session = LoginSessionProxy::builder(&connection)
+        .path(path)?
+        .build()
+        .await?;
+
+    let mut changes = session.receive_locked_hint_changed().await;
+    set_locked(session.locked_hint().await?);
+    while let Some(change) = changes.next().await {
+        set_locked(change.get().await?);
+    }
+    Ok(())
+}
+
+/// Starts watching the session lock on the system bus in the background.
+pub fn start() {
+    async_std::task::spawn(async {
+        let result = match zbus::Connection::system().await {
+            Ok(connection) => watch(connection).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!(
+                ?e,
+                "Cannot watch the session lock, contents are shown while locked"
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        time::Duration,
+    };
+
+    use zbus::dbus_interface;
+
+    use super::*;
+
+    const SESSION_PATH: &str = "/org/freedesktop/login1/session/_31";
+
+    struct Manager;
+
+    #[dbus_interface(name = "org.freedesktop.login1.Manager")]
+    impl Manager {
+        fn get_session(&self, _session_id: &str) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(SESSION_PATH).unwrap()
+        }
+    }
+
+    struct Session {
+        locked: bool,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.login1.Session")]
+    impl Session {
+        #[dbus_interface(property)]
+        fn locked_hint(&self) -> bool {
+            self.locked
+        }
+    }
+
+    /// A private bus, killed on drop.
+    struct Bus(Child, String);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+        }
+    }
+
+    fn private_bus() -> Bus {
+        let mut child = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("cannot start dbus-daemon");
+        let mut address = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Bus(child, address.trim().to_string())
+    }
+
+    async fn wait_until(locked: bool) {
+        for _ in 0..100 {
+            if is_locked() == locked {
+                return;
+            }
+            async_std::task::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("lock state never became {locked}");
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon, run with --ignored"]
+    fn follows_locked_hint() {
+        let bus = private_bus();
+        async_std::task::block_on(async {
+            let logind = zbus::ConnectionBuilder::address(bus.1.as_str())
+                .unwrap()
+                .name("org.freedesktop.login1")
+                .unwrap()
+                .serve_at("/org/freedesktop/login1", Manager)
+                .unwrap()
+                .serve_at(SESSION_PATH, Session { locked: true })
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+            let client = zbus::ConnectionBuilder::address(bus.1.as_str())
+                .unwrap()
+                .build()
+                .await
+                .unwrap();
+
+            async_std::task::spawn(watch(client));
+            wait_until(true).await;
+
+            let session = (logind.object_server())
+                .interface::<_, Session>(SESSION_PATH)
+                .await
+                .unwrap();
+            session.get_mut().await.locked = false;
+            (session.get().await)
+                .locked_hint_changed(session.signal_context())
+                .await
+                .unwrap();
+            wait_until(false).await;
+        });
+    }
+}
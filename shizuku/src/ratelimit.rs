@@ -0,0 +1,207 @@
+//! Flood protection for `Notify`.
+//!
+//! Every sender gets a token bucket, keyed on its D-Bus unique name and the app name it sends
+//! with. A notification takes a token, and tokens come back over time, so that an app can send a
+//! burst of notifications but not keep on flooding. Notifications over the limit are refused
+//! with a D-Bus error, and counted in a single toast instead of being shown.
+//!
+//! ```toml
+//! [rate_limit]
+//! burst = 10
+//! refill = 1.0
+//! ```
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::{dbus::Urgency, widget::Notification, NotifSchedTimer, NotifStackEvent};
+
+lazy_static::lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<(String, String), Bucket>> = Mutex::new(HashMap::new());
+}
+
+/// Limits of the token buckets.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Notifications a sender can send at once, `0` to disable rate limiting.
+    pub burst: u32,
+    /// Notifications per second a sender gets back.
+    pub refill: f64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            burst: 10,
+            refill: 1.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// Notifications refused since the bucket ran out.
+    dropped: u32,
+    /// Toast counting the refused notifications.
+    flood_id: Option<u32>,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            last: now,
+            dropped: 0,
+            flood_id: None,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill).min(f64::from(limit.burst));
+        self.last = now;
+    }
+
+    /// Takes a token, returns whether there was one.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        // the flood is only over once the sender was quiet long enough to get all its tokens back,
+        // a sender that keeps going keeps updating the same toast
+        if self.is_full(limit) {
+            self.dropped = 0;
+            self.flood_id = None;
+        }
+        if self.tokens < 1.0 {
+            self.dropped += 1;
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= f64::from(limit.burst)
+    }
+}
+
+/// Takes a token for a notification from `sender` as `app_name`.
+///
+/// Over the limit, the toast counting refused notifications is shown or updated, and an error
+/// for the caller is returned.
+#[tracing::instrument]
+pub fn check(sender: &str, app_name: &str) -> zbus::fdo::Result<()> {
+    let limit = crate::config::get().rate_limit.clone();
+    if limit.burst == 0 {
+        return Ok(());
+    }
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap();
+    // forget about senders that have been quiet long enough to be back to a full bucket
+    buckets.retain(|_, bucket| {
+        bucket.refill(&limit, now);
+        !bucket.is_full(&limit)
+    });
+
+    let bucket = (buckets.entry((sender.to_string(), app_name.to_string())))
+        .or_insert_with(|| Bucket::new(&limit, now));
+    if bucket.take(&limit, now) {
+        return Ok(());
+    }
+
+    warn!(dropped = bucket.dropped, "Sender is over the rate limit");
+    let id = *bucket
+        .flood_id
+        .get_or_insert_with(crate::dbus::get_notification_id);
+    let notif = flood_notification(id, app_name, bucket.dropped);
+    drop(buckets);
+    // the toast already on screen gets updated in place
+    crate::send_event(NotifStackEvent::Added(notif));
+
+    Err(zbus::fdo::Error::LimitsExceeded(format!(
+        "Too many notifications, try again in {:.0} secs",
+        (1.0 / limit.refill.max(f64::EPSILON)).ceil()
+    )))
+}
+
+/// The toast counting the notifications refused from an app.
+fn flood_notification(id: u32, app_name: &str, dropped: u32) -> Notification {
+    debug!(id, app_name, dropped, "Updating flood notif");
+    let name = if app_name.is_empty() {
+        "An app"
+    } else {
+        app_name
+    };
+    Notification {
+        app_name: app_name.to_string(),
+        title: glib::markup_escape_text(&format!("{name} sent {dropped} notifications")).into(),
+        body: "They were not shown because they came in too fast.".to_string(),
+        icon: Some("dialog-warning-symbolic".to_string()),
+        urgency: Urgency::Normal,
+        id,
+        sched: NotifSchedTimer::from_expire_timeout(-1, Urgency::Normal),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn bucket_refills() {
+        let limit = RateLimit {
+            burst: 3,
+            refill: 2.0,
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::new(&limit, start);
+
+        assert!((0..3).all(|_| bucket.take(&limit, start)));
+        assert!(!bucket.take(&limit, start));
+        assert!(!bucket.take(&limit, start));
+        assert_eq!(bucket.dropped, 2);
+
+        // half a second gives one token back, but the flood goes on
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(&limit, later));
+        assert_eq!(bucket.dropped, 2);
+        assert!(!bucket.take(&limit, later));
+        assert_eq!(bucket.dropped, 3);
+
+        // never more than the burst
+        let much_later = later + Duration::from_secs(60);
+        bucket.refill(&limit, much_later);
+        assert!(bucket.is_full(&limit));
+        assert!((0..3).all(|_| bucket.take(&limit, much_later)));
+        assert_eq!(bucket.dropped, 0);
+        assert!(!bucket.take(&limit, much_later));
+    }
+
+    #[test]
+    fn sustained_flood_updates_one_toast() {
+        let _lock = crate::test_lock();
+        let key = (":1.42".to_string(), "flooder".to_string());
+        let burst = crate::config::get().rate_limit.burst;
+        let refused = (0..burst * 2)
+            .filter(|_| check(&key.0, &key.1).is_err())
+            .count();
+        assert_eq!(refused, burst as usize);
+        let flood_id = || BUCKETS.lock().unwrap()[&key].flood_id;
+        let first = flood_id().expect("flood toast is shown");
+
+        // the sender keeps going faster than tokens come back
+        for _ in 0..5 {
+            BUCKETS.lock().unwrap().get_mut(&key).unwrap().last -= Duration::from_secs(1);
+            assert!(check(&key.0, &key.1).is_ok());
+            assert!(check(&key.0, &key.1).is_err());
+            assert_eq!(flood_id(), Some(first));
+        }
+        assert_eq!(BUCKETS.lock().unwrap()[&key].dropped, burst + 5);
+    }
+}
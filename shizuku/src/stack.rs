@@ -0,0 +1,452 @@
+//! The notifications on screen.
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::atomic::Ordering,
+};
+
+use gtk::prelude::{GtkWindowExt, WidgetExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+use crate::{dbus::CloseReason, widget};
+
+/// What [NotificationStack::add] did with a notif.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Added {
+    Shown,
+    /// The notif was on screen already, and got replaced.
+    Updated,
+    /// Do Not Disturb is on, the notif went straight to the history.
+    DoNotDisturb,
+    /// The app is muted, the notif went straight to the history.
+    Muted,
+}
+
+/// Notifications from the same application, shown in a single toast window.
+///
+/// The window shows the latest notification with a count of the others, and can be expanded into
+/// the full list.
+#[derive(Clone, Debug)]
+pub struct NotifGroup {
+    /// See [widget::Notification::group_key].
+    pub key: String,
+    /// Notif ids only ever go up, so this goes from the oldest to the newest notif.
+    pub notifs: BTreeMap<u32, widget::Notification>,
+    pub win: libhelium::Window,
+    pub expanded: bool,
+    /// Offset the window was last placed at, see [NotificationStack::relayout].
+    offset: Option<i32>,
+}
+
+impl NotifGroup {
+    /// Rebuilds the window content from the notifs in the group.
+    pub fn rebuild(&self) {
+        let notifs = self.notifs.values().collect::<Vec<_>>();
+        let content = widget::build_group(&self.key, &notifs, self.expanded);
+        self.win.set_child(Some(&content));
+    }
+}
+
+/// A notif hidden until its timer is over, see [NotificationStack::snooze].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snoozed {
+    pub notif: widget::Notification,
+    pub until: crate::NotifSchedTimer,
+}
+
+/// Number of closed notifs kept around to be restored.
+const HISTORY_SIZE: usize = 50;
+
+#[derive(Clone, Default)]
+pub struct NotificationStack {
+    /// The groups of notifications on screen, from the oldest to the newest.
+    groups: Vec<NotifGroup>,
+    /// Closed notifs, the most recent last.
+    history: VecDeque<widget::Notification>,
+    /// Notifs hidden until later. They are still open as far as their app is concerned.
+    snoozed: Vec<Snoozed>,
+    /// Keys of the groups whose notifs go straight to the history.
+    muted: BTreeSet<String>,
+}
+
+impl NotificationStack {
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+    }
+
+    /// A stack with nothing on screen, previously closed notifs in its history, and notifs
+    /// snoozed before a restart. Those keep their timers, and the ones over while the daemon
+    /// wasn't running are shown on the next [NotificationStack::wake_snoozed].
+    pub fn restored(
+        history: Vec<widget::Notification>,
+        snoozed: Vec<Snoozed>,
+        muted: BTreeSet<String>,
+    ) -> Self {
+        for Snoozed { notif, .. } in &snoozed {
+            crate::dbus::reopen(notif.id);
+        }
+        let mut stack = Self {
+            snoozed,
+            muted,
+            ..Self::default()
+        };
+        history
+            .into_iter()
+            .for_each(|notif| stack.push_history(notif));
+        stack
+    }
+
+    /// Closed notifs, the most recent last.
+    pub fn history(&self) -> impl Iterator<Item = &widget::Notification> {
+        self.history.iter()
+    }
+
+    pub fn snoozed(&self) -> &[Snoozed] {
+        &self.snoozed
+    }
+
+    pub fn muted(&self) -> &BTreeSet<String> {
+        &self.muted
+    }
+
+    /// Whether nothing is on screen.
+    ///
+    /// Snoozed notifs don't count, they are saved and shown once the daemon is started again.
+    pub fn is_idle(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    fn push_history(&mut self, notif: widget::Notification) {
+        if self.history.len() >= HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(notif);
+    }
+
+    /// Moves a notif that won't be shown straight to the history, and tells the sender it is
+    /// closed so that it doesn't wait on it.
+    fn suppress(&mut self, notif: widget::Notification) {
+        crate::dbus::emit_notification_closed(notif.id, CloseReason::Undefined);
+        self.push_history(notif);
+    }
+
+    fn group_of(&self, id: u32) -> Option<usize> {
+        self.groups
+            .iter()
+            .position(|group| group.notifs.contains_key(&id))
+    }
+
+    fn group_by_key(&self, key: &str) -> Option<usize> {
+        self.groups.iter().position(|group| group.key == key)
+    }
+
+    /// Adds a [widget::Notification] into the stack and shows it, in the group of its app.
+    ///
+    /// A notif with the same id already on screen is updated in place.
+    pub fn add(&mut self, notif: widget::Notification, app: &libhelium::Application) -> Added {
+        let key = notif.group_key();
+        let span = tracing::debug_span!("add_notif", id = notif.id, key);
+        let _enter = span.enter();
+
+        // notifs already on screen are updated in place
+        if let Some(index) = self.group_of(notif.id) {
+            debug!("Updating notif");
+            let group = &mut self.groups[index];
+            group.notifs.insert(notif.id, notif);
+            group.rebuild();
+            self.relayout();
+            return Added::Updated;
+        }
+
+        if crate::dbus::DO_NOT_DISTURB.load(Ordering::Relaxed) && !notif.bypasses_dnd() {
+            debug!("Do Not Disturb is on, moving notif to history");
+            self.suppress(notif);
+            return Added::DoNotDisturb;
+        }
+        if self.muted.contains(&key) && !notif.bypasses_dnd() {
+            debug!("App is muted, moving notif to history");
+            self.suppress(notif);
+            return Added::Muted;
+        }
+        debug!("Adding new notif");
+
+        if let Some(index) = self.group_by_key(&key) {
+            let group = &mut self.groups[index];
+            group.notifs.insert(notif.id, notif);
+            group.rebuild();
+        } else {
+            let win = widget::new_window(app, &notif.app_name);
+            win.set_widget_name(&format!("notif-group-{key}"));
+            let group = NotifGroup {
+                key,
+                notifs: BTreeMap::from([(notif.id, notif)]),
+                win,
+                expanded: false,
+                offset: None,
+            };
+            group.rebuild();
+            self.groups.push(group);
+        }
+
+        self.relayout();
+        trace!("Setting window as visible");
+        for group in &self.groups {
+            group.win.set_visible(true);
+        }
+        Added::Shown
+    }
+
+    /// Checks for notifications that have timed out and removes one.
+    ///
+    /// Once a timed-out notification (determined by [crate::NotifSchedTimer::is_over]) is found,
+    /// it is removed from its group, and the group window is closed if it was the last one.
+    #[tracing::instrument(skip(self))]
+    pub fn poll(&mut self) {
+        let expired = (self.groups.iter())
+            .flat_map(|group| group.notifs.values())
+            .find(|notif| notif.sched.is_over())
+            .map(|notif| notif.id);
+        if let Some(id) = expired {
+            debug!(id, "Closing timed out notif");
+            self.remove(id, CloseReason::Expired);
+        }
+        // windows get their real size once they are allocated, so keep the placement up to date
+        self.relayout();
+    }
+
+    /// Takes a notif off the screen, closing the window of its group if it was the last one.
+    fn take(&mut self, id: u32) -> Option<widget::Notification> {
+        let index = self.group_of(id)?;
+        let group = &mut self.groups[index];
+        let notif = group.notifs.remove(&id);
+        if group.notifs.is_empty() {
+            group.win.close();
+            self.groups.remove(index);
+        } else {
+            group.rebuild();
+        }
+        self.relayout();
+        notif
+    }
+
+    /// Removes a single notif, on screen or snoozed, and emits `NotificationClosed` for it.
+    #[tracing::instrument(skip(self))]
+    pub fn remove(&mut self, id: u32, reason: CloseReason) {
+        debug!("Removing notif");
+        let notif = self.take(id).or_else(|| {
+            let index = self.snoozed.iter().position(|s| s.notif.id == id)?;
+            Some(self.snoozed.remove(index).notif)
+        });
+        let Some(notif) = notif else {
+            return warn!("notif not found");
+        };
+        trace!(?notif, "notif removed");
+        widget::clear_reply_draft(id);
+        crate::dbus::emit_notification_closed(id, reason);
+        crate::audit::closed(id, reason);
+        self.push_history(notif);
+    }
+
+    /// Removes every notif in a group, emitting `NotificationClosed` for each of them.
+    #[tracing::instrument(skip(self))]
+    pub fn remove_group(&mut self, key: &str, reason: CloseReason) {
+        debug!("Removing notif group");
+        let Some(index) = self.group_by_key(key) else {
+            return warn!("notif group not found");
+        };
+        let group = self.groups.remove(index);
+        group.win.close();
+        for (id, notif) in group.notifs {
+            widget::clear_reply_draft(id);
+            crate::dbus::emit_notification_closed(id, reason);
+            crate::audit::closed(id, reason);
+            self.push_history(notif);
+        }
+        self.relayout();
+    }
+
+    /// Emits `ActionInvoked` for a notif, then dismisses it.
+    #[tracing::instrument(skip(self))]
+    pub fn invoke_action(&mut self, id: u32, key: String) {
+        debug!("Invoking action");
+        crate::audit::action_invoked(id, &key);
+        crate::dbus::emit_action_invoked(id, key);
+        self.remove(id, CloseReason::Dismissed);
+    }
+
+    /// Emits `NotificationReplied` for a notif, then dismisses it.
+    #[tracing::instrument(skip(self, text))]
+    pub fn reply(&mut self, id: u32, text: String) {
+        debug!("Sending inline reply");
+        crate::audit::action_invoked(id, crate::dbus::INLINE_REPLY_ACTION);
+        crate::dbus::emit_notification_replied(id, text);
+        self.remove(id, CloseReason::Dismissed);
+    }
+
+    /// Keeps a notif on screen while the user types a reply to it.
+    #[tracing::instrument(skip(self))]
+    pub fn set_replying(&mut self, id: u32, replying: bool) {
+        let Some(notif) = (self.groups.iter_mut()).find_map(|group| group.notifs.get_mut(&id))
+        else {
+            return warn!("notif not found");
+        };
+        if replying {
+            notif.sched.pause();
+        } else if !notif.expanded {
+            notif.sched.resume();
+        }
+    }
+
+    /// Hides a notif until `until` is over, then shows it again.
+    ///
+    /// Its app isn't told that it was closed until it is shown again and closed for good.
+    /// Returns whether the notif was on screen.
+    #[tracing::instrument(skip(self))]
+    pub fn snooze(&mut self, id: u32, until: crate::NotifSchedTimer) -> bool {
+        let Some(mut notif) = self.take(id) else {
+            warn!("notif not found");
+            return false;
+        };
+        debug!("Snoozing notif");
+        notif.expanded = false;
+        self.snoozed.push(Snoozed { notif, until });
+        true
+    }
+
+    /// Shows the snoozed notifs whose snooze is over, returns whether there were any.
+    pub fn wake_snoozed(&mut self, app: &libhelium::Application) -> bool {
+        let (woken, snoozed) = (std::mem::take(&mut self.snoozed).into_iter())
+            .partition::<Vec<_>, _>(|s| s.until.is_over());
+        self.snoozed = snoozed;
+        let any = !woken.is_empty();
+        for Snoozed { mut notif, .. } in woken {
+            debug!(id = notif.id, "Snooze is over");
+            notif.sched = crate::NotifSchedTimer::from_expire_timeout(-1, notif.urgency);
+            _ = self.add(notif, app);
+        }
+        any
+    }
+
+    /// Stops showing notifs from a group, and dismisses the ones on screen.
+    ///
+    /// Like with Do Not Disturb, critical notifs are still shown.
+    #[tracing::instrument(skip(self))]
+    pub fn mute(&mut self, key: String) {
+        debug!("Muting notif group");
+        if self.group_by_key(&key).is_some() {
+            self.remove_group(&key, CloseReason::Dismissed);
+        }
+        self.muted.insert(key);
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn unmute(&mut self, key: &str) {
+        debug!("Unmuting notif group");
+        self.muted.remove(key);
+    }
+
+    /// Dismisses the most recent notif on screen.
+    pub fn dismiss_newest(&mut self) {
+        let newest = (self.groups.iter())
+            .filter_map(|group| group.notifs.keys().next_back())
+            .max()
+            .copied();
+        if let Some(id) = newest {
+            self.remove(id, CloseReason::Dismissed);
+        }
+    }
+
+    /// Dismisses every notif on screen.
+    pub fn dismiss_all(&mut self) {
+        let keys = self
+            .groups
+            .iter()
+            .map(|group| group.key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.remove_group(&key, CloseReason::Dismissed);
+        }
+    }
+
+    /// Shows the last closed notif again, with a fresh expiration timer.
+    #[tracing::instrument(skip(self, app))]
+    pub fn restore_last(&mut self, app: &libhelium::Application) {
+        let Some(mut notif) = self.history.pop_back() else {
+            return debug!("No notif to restore");
+        };
+        debug!(id = notif.id, "Restoring notif");
+        notif.sched = crate::NotifSchedTimer::from_expire_timeout(-1, notif.urgency);
+        notif.expanded = false;
+        _ = self.add(notif, app);
+    }
+
+    /// Expands a group into the full list of its notifs, or collapses it back.
+    #[tracing::instrument(skip(self))]
+    pub fn toggle_group(&mut self, key: &str) {
+        let Some(index) = self.group_by_key(key) else {
+            return warn!("notif group not found");
+        };
+        let group = &mut self.groups[index];
+        group.expanded = !group.expanded;
+        group.rebuild();
+        self.relayout();
+    }
+
+    /// Expands a notif to show its full body, or collapses it back.
+    ///
+    /// The expiration timer is paused while the notif is expanded.
+    #[tracing::instrument(skip(self))]
+    pub fn toggle_expanded(&mut self, id: u32) {
+        let Some(index) = self.group_of(id) else {
+            return warn!("notif not found");
+        };
+        let group = &mut self.groups[index];
+        let Some(notif) = group.notifs.get_mut(&id) else {
+            return;
+        };
+        notif.expanded = !notif.expanded;
+        if notif.expanded {
+            notif.sched.pause();
+        } else {
+            notif.sched.resume();
+        }
+        group.rebuild();
+        self.relayout();
+    }
+
+    /// Moves every window to its position in the stack, closing gaps left by removed notifs.
+    pub fn relayout(&mut self) {
+        let (gap, min_height) = {
+            let config = crate::config::get();
+            (config.margin.gap, config.height)
+        };
+        let mut offset = 0;
+        for group in &mut self.groups {
+            if group.offset != Some(offset) {
+                widget::place_window(&group.win, offset);
+                group.offset = Some(offset);
+            }
+            offset += group.win.height().max(min_height) + gap;
+        }
+    }
+
+    /// Rebuilds every toast on screen with the current config.
+    #[tracing::instrument(skip(self))]
+    pub fn restyle(&mut self) {
+        debug!("Restyling notifs");
+        for group in &mut self.groups {
+            group.rebuild();
+            // margins may have changed too
+            group.offset = None;
+        }
+        self.relayout();
+    }
+
+    pub fn get(&self, id: u32) -> Option<&widget::Notification> {
+        self.groups.iter().find_map(|group| group.notifs.get(&id))
+    }
+}
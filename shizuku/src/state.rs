@@ -0,0 +1,76 @@
+//! State that survives restarts of the daemon, e.g. when it exits on idle.
+//!
+//! This is stored in `$XDG_STATE_HOME/shizuku/state.json` (usually
+//! `~/.local/state/shizuku/state.json`) when the daemon quits, a notif is snoozed or Do Not
+//! Disturb is toggled, and read back on startup. Muted apps and the CAP alerts already dealt
+//! with are kept there too.
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{stack::Snoozed, widget::Notification};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub do_not_disturb: bool,
+    /// Last notif id handed out, so that ids are not reused after a restart.
+    pub last_id: u32,
+    /// Closed notifs, the most recent last. Their `image-data` is not kept, it can be megabytes.
+    pub history: Vec<Notification>,
+    /// Notifs put away until later, see [crate::stack::NotificationStack::snooze].
+    pub snoozed: Vec<Snoozed>,
+    /// Keys of the groups muted from the context menu of toasts.
+    pub muted: BTreeSet<String>,
+    /// CAP alerts already delivered and no longer on screen, by identifier.
+    pub cap_alerts: HashMap<String, crate::cap::source::Seen>,
+}
+
+pub fn state_path() -> PathBuf {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })
+        .unwrap_or_default()
+        .join("shizuku")
+        .join("state.json")
+}
+
+impl State {
+    /// Reads the state file, falling back to an empty state if it is missing or invalid.
+    #[tracing::instrument]
+    pub fn load() -> Self {
+        let path = state_path();
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                debug!(?e, ?path, "No state to restore");
+                return Self::default();
+            }
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!(?e, ?path, "Invalid state file, starting afresh");
+            Self::default()
+        })
+    }
+
+    /// Writes the state file.
+    #[tracing::instrument(skip(self))]
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = state_path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // write then rename, so that a crash never leaves a half-written file behind
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, &path)?;
+        debug!(?path, "Saved state");
+        Ok(())
+    }
+}
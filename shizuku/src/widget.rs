@@ -1,13 +1,28 @@
-use crate::dbus::Urgency;
-use glib::object::Cast;
-use gtk::prelude::{BoxExt, ButtonExt, GtkWindowExt, WidgetExt};
-use gtk4_layer_shell::{Edge, Layer, LayerShell};
-use tracing::{debug, warn};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+use crate::config::{KeyAction, MouseAction};
+use crate::dbus::{CloseReason, Urgency, DEFAULT_ACTION, INLINE_REPLY_ACTION};
+use gtk::prelude::{
+    BoxExt, ButtonExt, Cast, EditableExt, EntryExt, GestureExt, GestureSingleExt, GtkWindowExt,
+    ObjectExt, PopoverExt, WidgetExt,
+};
+use gtk4_layer_shell::{Edge, KeyboardMode, Layer, LayerShell};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+thread_local! {
+    /// Text typed in reply entries, so that it survives toasts being rebuilt.
+    static REPLY_DRAFTS: RefCell<HashMap<u32, String>> = RefCell::new(HashMap::new());
+}
 
-// top margin would be the default (window size * index)+ margin of 50
-// if index is 0 then give it 15
-const TOP_OFFSET: usize = 15;
-const WINDOW_HEIGHT: usize = 100;
+/// Forgets the reply typed for a notif, once it is closed.
+pub fn clear_reply_draft(id: u32) {
+    REPLY_DRAFTS.with(|drafts| drafts.borrow_mut().remove(&id));
+}
 
 // thread_local! {
 //     pub static GTK_WINDOWS: std::sync::Arc<std::sync::Mutex<Vec<libhelium::Window>>> = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
@@ -39,24 +54,171 @@ const WINDOW_HEIGHT: usize = 100;
 
 // }
 
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Notification {
+    pub app_name: String,
     pub title: String,
     pub body: String,
     pub icon: Option<String>,
     pub urgency: Urgency,
+    pub category: Option<String>,
+    pub desktop_entry: Option<String>,
     pub id: u32,
     pub sched: crate::NotifSchedTimer,
     pub image_data: Option<crate::icon::ImageData>,
+    /// Action keys and their labels.
+    pub actions: Vec<(String, String)>,
+    /// Placeholder of the reply entry, from the `x-kde-reply-placeholder-text` hint.
+    pub reply_placeholder: Option<String>,
+    /// URIs of attached files, from the `x-kde-urls` hint. See [crate::attachment].
+    pub attachments: Vec<String>,
+    /// Whether the full body is shown, see [Notification::build_content].
+    #[serde(skip)]
+    pub expanded: bool,
     // pub destroy_hdl_id: u64,
 }
 
+/// Creates an empty toast [libhelium::Window].
+///
+/// The window is placed in the stack with [place_window].
+pub fn new_window(app: &libhelium::Application, title: &str) -> libhelium::Window {
+    let window = libhelium::Window::builder()
+        .title(title)
+        .application(app)
+        .resizable(false)
+        .decorated(false)
+        // Set opacity to be barely transparent, works around https://github.com/WayfireWM/wayfire/issues/2125
+        // NOTE: Only 2 decimal places work, 3 or more will round up to 1.0, thus making it opaque again
+        .opacity(0.99)
+        .css_classes(vec!["surface-container-lowest-bg-color", "x-large-radius"])
+        .css_name("notif-toast")
+        .build();
+    // without layer shell, e.g. on headless test displays, toasts are plain windows
+    if !gtk4_layer_shell::is_supported() {
+        return window;
+    }
+    window.init_layer_shell();
+    window.set_layer(Layer::Overlay);
+    window.set_namespace(Some("notification"));
+    // toasts never steal focus, they can only be focused by clicking them while hovered
+    window.set_keyboard_mode(KeyboardMode::None);
+    let hover = gtk::EventControllerMotion::new();
+    let weak = window.downgrade();
+    hover.connect_enter(move |_, _, _| {
+        if let Some(window) = weak.upgrade() {
+            if window.keyboard_mode() == KeyboardMode::None {
+                window.set_keyboard_mode(KeyboardMode::OnDemand);
+            }
+        }
+    });
+    let weak = window.downgrade();
+    hover.connect_leave(move |_| {
+        if let Some(window) = weak.upgrade() {
+            if window.keyboard_mode() == KeyboardMode::OnDemand {
+                window.set_keyboard_mode(KeyboardMode::None);
+            }
+        }
+    });
+    window.add_controller(hover.clone());
+
+    // a reply entry grabs the keyboard while focused, see [Notification::build_reply]. Give it
+    // back from here, the entry may be gone by the time it loses the focus.
+    window.connect_focus_widget_notify(move |window| {
+        let typing = (window.focus_widget()).is_some_and(|widget| widget.is::<gtk::Text>());
+        if !typing && window.keyboard_mode() == KeyboardMode::Exclusive {
+            window.set_keyboard_mode(if hover.contains_pointer() {
+                KeyboardMode::OnDemand
+            } else {
+                KeyboardMode::None
+            });
+        }
+    });
+    window.connect_is_active_notify(|window| {
+        if !window.is_active() {
+            window.set_keyboard_mode(KeyboardMode::None);
+        }
+    });
+    window.connect_unrealize(|window| window.set_keyboard_mode(KeyboardMode::None));
+
+    window.auto_exclusive_zone_enable();
+
+    window
+}
+
 impl Notification {
-    /// Creates [libhelium::Window] for the [Notification].
+    /// Key of the group this notif is shown in, see [crate::stack::NotifGroup].
+    ///
+    /// Notifs are grouped on their desktop entry, or their app name if they don't have one.
+    pub fn group_key(&self) -> String {
+        (self.desktop_entry.clone())
+            .filter(|entry| !entry.is_empty())
+            .or_else(|| Some(self.app_name.clone()).filter(|name| !name.is_empty()))
+            // don't group notifs that don't tell where they come from
+            .unwrap_or_else(|| format!("#{}", self.id))
+    }
+
+    /// Whether the notif is shown even with Do Not Disturb on.
+    pub fn bypasses_dnd(&self) -> bool {
+        self.urgency == Urgency::Critical || self.category.as_deref() == Some(crate::cap::CATEGORY)
+    }
+
+    /// Summary and body without markup, e.g. to copy them.
+    pub fn plain_text(&self) -> String {
+        let strip = |markup: &str| {
+            gtk::pango::parse_markup(markup, '\0')
+                .map_or_else(|_| markup.to_string(), |(_, text, _)| text.to_string())
+        };
+        let (title, body) = (strip(&self.title), strip(&self.body));
+        if body.is_empty() {
+            title
+        } else {
+            format!("{title}\n{body}")
+        }
+    }
+
+    /// What is shown of the notif while its contents are hidden, see [crate::lock].
+    fn redacted(&self) -> Self {
+        let icon = (self.icon.clone())
+            // only keep icon names, files are likely pictures sent along
+            .filter(|icon| !std::path::Path::new(icon).is_file());
+        Self {
+            app_name: self.app_name.clone(),
+            title: glib::markup_escape_text(&self.app_name).into(),
+            body: "New notification".to_string(),
+            icon,
+            urgency: self.urgency,
+            category: self.category.clone(),
+            desktop_entry: self.desktop_entry.clone(),
+            id: self.id,
+            sched: self.sched.clone(),
+            expanded: self.expanded,
+            ..Default::default()
+        }
+    }
+
+    /// Builds the content of the toast according to the current [crate::config::Config].
+    ///
+    /// This is also used to restyle toasts already on screen after the config is reloaded.
+    /// `close_event` is sent to the stack when the toast is closed, be it with the close button, a
+    /// key or a click, so that they all close the same notifs.
+    ///
+    /// Clicking the toast or activating it with the keyboard expands it by default: the body is
+    /// shown in full in a scrollable area, and the image is shown larger. See
+    /// [crate::config::Keys] and [crate::config::Mouse] for the bindings on a toast.
     ///
-    /// `index` = number of active notifications not including this new one, i.e. what the index of
-    /// this notification will be once it gets added into the stack.
-    pub fn as_window(&mut self, app: &libhelium::Application, index: usize) -> libhelium::Window {
+    /// While the session is locked, only the app name is shown, see [crate::lock].
+    pub fn build_content(&self, close_event: crate::NotifStackEvent) -> gtk::Box {
+        if crate::lock::hides(self) {
+            self.redacted().build_toast(close_event)
+        } else {
+            self.build_toast(close_event)
+        }
+    }
+
+    fn build_toast(&self, close_event: crate::NotifStackEvent) -> gtk::Box {
+        let config = crate::config::get();
+
         let box_ = gtk::Box::builder()
             .orientation(gtk::Orientation::Horizontal)
             .spacing(10)
@@ -64,50 +226,113 @@ impl Notification {
             .margin_bottom(10)
             .margin_start(0)
             .margin_end(10)
-            .width_request(400)
-            .height_request(WINDOW_HEIGHT as i32)
+            .width_request(config.width)
+            .height_request(config.height)
             .build();
-        // force box size to be 400x100 no matter what
-        box_.set_size_request(400, WINDOW_HEIGHT as i32);
-
-        let window = libhelium::Window::builder()
-            .title(&self.title)
-            .application(app)
-            .resizable(false)
-            .decorated(false)
-            // Set opacity to be barely transparent, works around https://github.com/WayfireWM/wayfire/issues/2125
-            // NOTE: Only 2 decimal places work, 3 or more will round up to 1.0, thus making it opaque again
-            .opacity(0.99)
-            .css_classes(vec!["surface-container-lowest-bg-color", "x-large-radius"])
-            .css_name("notif-toast")
-            .build();
-        window.init_layer_shell();
-        window.set_layer(Layer::Overlay);
-        window.set_namespace(Some("notification"));
-
-        // force window size to be 400x100 no matter what
-
-        window.set_size_request(400, WINDOW_HEIGHT as i32);
-
-        // FIXME: wrong placaement when manually close notif then make more (will duplicate at same
-        // position due to using index of stack (which has new shorter length))
-        let top_margin = (WINDOW_HEIGHT + 50) * index + TOP_OFFSET;
-
-        debug!(?top_margin);
-
-        window.auto_exclusive_zone_enable();
+        // force box size no matter what
+        box_.set_size_request(config.width, config.height);
+        box_.set_focusable(true);
 
-        window.set_anchor(Edge::Top, true);
-        window.set_anchor(Edge::Right, true);
-        window.set_anchor(Edge::Bottom, false);
-        window.set_anchor(Edge::Left, false);
+        let id = self.id;
 
-        window.set_margin(Edge::Top, top_margin as i32 + 15);
-        window.set_margin(Edge::Right, 15);
-
-        // let mut windows = GTK_WINDOWS.with(|windows| windows.lock().unwrap().clone());
+        let icon_size = if self.expanded {
+            config.icon_size * 2
+        } else {
+            config.icon_size
+        };
 
-        // windows.push(window.clone());
+        // every action but the default one gets a button, the default one is invoked with the
+        // keyboard, and the inline reply one gets a text entry
+        let has_default = self.actions.iter().any(|(key, _)| key == DEFAULT_ACTION);
+        let action_buttons = (self.actions.iter())
+            .filter(|(key, _)| key != DEFAULT_ACTION && key != INLINE_REPLY_ACTION)
+            .map(|(key, label)| {
+                let button = gtk::Button::builder()
+                    .label(label)
+                    .css_classes(vec!["pill"])
+                    .build();
+                let key = key.clone();
+                button.connect_clicked(move |_| {
+                    crate::send_event(crate::NotifStackEvent::ActionInvoked(id, key.clone()));
+                });
+                button
+            })
+            .collect::<Vec<_>>();
+
+        // the default action, or the first one with a button
+        let do_action = (self.actions.iter())
+            .find(|(key, _)| key == DEFAULT_ACTION)
+            .or_else(|| (self.actions.iter()).find(|(key, _)| key != INLINE_REPLY_ACTION))
+            .map(|(key, _)| key.clone());
+        let click = gtk::GestureClick::builder().button(0).build();
+        let (weak, group_key, text) = (box_.downgrade(), self.group_key(), self.plain_text());
+        let click_close = close_event.clone();
+        click.connect_released(move |gesture, _, x, y| {
+            let action = crate::config::get()
+                .mouse
+                .action_for(gesture.current_button());
+            if action == MouseAction::None {
+                return;
+            }
+            gesture.set_state(gtk::EventSequenceState::Claimed);
+            debug!(?id, ?action, "Clicked toast");
+            match action {
+                MouseAction::None => {}
+                MouseAction::DoAction => {
+                    if let Some(key) = &do_action {
+                        crate::send_event(crate::NotifStackEvent::ActionInvoked(id, key.clone()));
+                    }
+                }
+                MouseAction::Close => crate::send_event(click_close.clone()),
+                MouseAction::CloseAll => crate::send_event(crate::NotifStackEvent::DismissAll),
+                MouseAction::ContextMenu => {
+                    if let Some(box_) = weak.upgrade() {
+                        popup_context_menu(box_.upcast_ref(), x, y, id, &group_key, &text);
+                    }
+                }
+                MouseAction::Expand => {
+                    crate::send_event(crate::NotifStackEvent::ExpandToggled(id));
+                }
+            }
+        });
+        box_.add_controller(click);
+
+        let replying = Rc::new(Cell::new(false));
+        let reply_box = (self.actions.iter())
+            .find(|(key, _)| key == INLINE_REPLY_ACTION)
+            .map(|(_, label)| self.build_reply(label, replying.clone()));
+
+        let keys = gtk::EventControllerKey::new();
+        let buttons = action_buttons.clone();
+        let dismiss_event = close_event.clone();
+        keys.connect_key_pressed(move |_, key, _, state| {
+            // keys typed in the reply entry are text, not bindings
+            if replying.get() {
+                return glib::Propagation::Proceed;
+            }
+            let Some(action) = crate::config::get().keys.action_for(key, state) else {
+                return glib::Propagation::Proceed;
+            };
+            debug!(?id, ?action, "Key pressed on toast");
+            match action {
+                KeyAction::Dismiss => crate::send_event(dismiss_event.clone()),
+                KeyAction::DefaultAction if has_default => crate::send_event(
+                    crate::NotifStackEvent::ActionInvoked(id, DEFAULT_ACTION.to_string()),
+                ),
+                KeyAction::DefaultAction | KeyAction::Expand => {
+                    crate::send_event(crate::NotifStackEvent::ExpandToggled(id));
+                }
+                KeyAction::CycleActions => {
+                    let next = (buttons.iter().position(WidgetExt::has_focus))
+                        .map_or(0, |i| (i + 1) % buttons.len());
+                    if let Some(button) = buttons.get(next) {
+                        button.grab_focus();
+                    }
+                }
+            }
+            glib::Propagation::Stop
+        });
+        box_.add_controller(keys);
 
         let textbox = gtk::Box::builder()
             .orientation(gtk::Orientation::Vertical)
@@ -124,7 +349,7 @@ impl Notification {
                 .icon_size(gtk::IconSize::Large)
                 .margin_start(20)
                 .margin_end(20)
-                .pixel_size(50)
+                .pixel_size(icon_size)
                 .css_classes(vec!["circle-radius"])
                 .halign(gtk::Align::Center)
                 .valign(gtk::Align::Center)
@@ -167,19 +392,52 @@ impl Notification {
             .label(&self.body)
             .use_markup(true)
             .halign(gtk::Align::Start)
-            .lines(3)
             .max_width_chars(30)
             .wrap(true)
             .wrap_mode(gtk::pango::WrapMode::WordChar)
             // .width_request(300)
-            .ellipsize(gtk::pango::EllipsizeMode::End)
             .build();
 
         // We add markup to the body too, conforming to XDG spec
         body.set_markup(&self.body);
 
         textbox.append(&title);
-        textbox.append(&body);
+        if self.expanded {
+            body.set_selectable(true);
+            let scroll = gtk::ScrolledWindow::builder()
+                .hscrollbar_policy(gtk::PolicyType::Never)
+                .propagate_natural_height(true)
+                .max_content_height(config.height * 3)
+                .child(&body)
+                .build();
+            textbox.append(&scroll);
+        } else {
+            body.set_lines(config.max_body_lines);
+            body.set_ellipsize(gtk::pango::EllipsizeMode::End);
+            textbox.append(&body);
+        }
+
+        if !self.attachments.is_empty() {
+            textbox.append(&crate::attachment::build_attachments(
+                &self.attachments,
+                icon_size,
+            ));
+        }
+
+        if !action_buttons.is_empty() {
+            let actions = gtk::Box::builder()
+                .orientation(gtk::Orientation::Horizontal)
+                .spacing(10)
+                .margin_bottom(10)
+                .build();
+            action_buttons
+                .iter()
+                .for_each(|button| actions.append(button));
+            textbox.append(&actions);
+        }
+        if let Some(reply_box) = &reply_box {
+            textbox.append(reply_box);
+        }
 
         box_.append(&textbox);
 
@@ -198,49 +456,297 @@ impl Notification {
             // .css_name("notif-close-btn")
             .build();
 
-        close_button.connect_clicked(move |btn| {
-            let Some(window) = btn
-                .parent()
-                .and_then(|action| action.parent())
-                .and_then(|box_| box_.parent())
-            else {
-                warn!("Can't get parent in connect clicked");
+        close_button.connect_clicked(move |_| {
+            debug!(?id, "Clicked close button");
+            crate::send_event(close_event.clone());
+        });
+
+        action_box.append(&close_button);
+        action_box.append(&build_snooze_menu(id));
+
+        box_.append(&action_box);
+
+        box_
+    }
+
+    /// Builds the text entry and send button for the [INLINE_REPLY_ACTION] action.
+    ///
+    /// Toasts only get the keyboard when clicked, which the compositor may take back at any time.
+    /// While the entry is focused, the window grabs the keyboard exclusively so that nothing typed
+    /// is lost, and the expiration timer is paused. `replying` is set meanwhile. The window gives
+    /// the keyboard back itself, see [new_window].
+    fn build_reply(&self, label: &str, replying: Rc<Cell<bool>>) -> gtk::Box {
+        let id = self.id;
+        let reply_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(10)
+            .margin_bottom(10)
+            .build();
+
+        let entry = gtk::Entry::builder()
+            .placeholder_text(self.reply_placeholder.as_deref().unwrap_or("Reply…"))
+            .hexpand(true)
+            .build();
+        if let Some(draft) = REPLY_DRAFTS.with(|drafts| drafts.borrow().get(&id).cloned()) {
+            entry.set_text(&draft);
+        }
+        entry.connect_changed(move |entry| {
+            let text = entry.text().to_string();
+            REPLY_DRAFTS.with(|drafts| drafts.borrow_mut().insert(id, text));
+        });
+
+        let focus = gtk::EventControllerFocus::new();
+        let (weak, replying_) = (entry.downgrade(), replying.clone());
+        focus.connect_enter(move |_| {
+            replying_.set(true);
+            if let Some(entry) = weak.upgrade() {
+                set_keyboard_mode(entry.upcast_ref(), KeyboardMode::Exclusive);
+            }
+            crate::send_event(crate::NotifStackEvent::ReplyFocused(id, true));
+        });
+        focus.connect_leave(move |_| {
+            replying.set(false);
+            crate::send_event(crate::NotifStackEvent::ReplyFocused(id, false));
+        });
+        entry.add_controller(focus);
+
+        let send = move |entry: &gtk::Entry| {
+            let text = entry.text().to_string();
+            if text.trim().is_empty() {
                 return;
-            };
+            }
+            debug!(?id, "Sending reply");
+            crate::send_event(crate::NotifStackEvent::Replied(id, text));
+        };
+        entry.connect_activate(send);
 
-            // downcast to libhelium::Window
-            let window = window
-                .downcast::<libhelium::Window>()
-                .expect("fail to downcast");
+        let label = if label.is_empty() { "Send" } else { label };
+        let send_button = gtk::Button::builder()
+            .label(label)
+            .css_classes(vec!["pill"])
+            .build();
+        let entry_ = entry.clone();
+        send_button.connect_clicked(move |_| send(&entry_));
 
-            let window_name = window.widget_name();
-            // parse window id by removing the "notif-" prefix
-            let window_id = window_name.split_at(6).1.parse::<u32>().unwrap();
+        reply_box.append(&entry);
+        reply_box.append(&send_button);
+        reply_box
+    }
+}
 
-            debug!(?window_name, ?window_id, "Clicked close button");
+/// Snooze durations offered on toasts.
+const SNOOZE_CHOICES: [(&str, fn() -> crate::NotifSchedTimer); 3] = [
+    ("For 5 minutes", || crate::NotifSchedTimer::in_secs(5 * 60)),
+    ("For 1 hour", || crate::NotifSchedTimer::in_secs(60 * 60)),
+    ("Until tomorrow", crate::NotifSchedTimer::tomorrow_morning),
+];
+
+/// Adds a flat button to a popover menu, which closes the popover when clicked.
+fn append_menu_item(
+    list: &gtk::Box,
+    popover: &gtk::Popover,
+    label: &str,
+    on_click: impl Fn() + 'static,
+) {
+    let button = gtk::Button::builder()
+        .label(label)
+        .css_classes(vec!["flat"])
+        .build();
+    let popover = popover.clone();
+    button.connect_clicked(move |_| {
+        popover.popdown();
+        on_click();
+    });
+    list.append(&button);
+}
 
-            // WORKAROUND: Let's send a message to close the window
+fn append_snooze_items(list: &gtk::Box, popover: &gtk::Popover, id: u32) {
+    for (label, until) in SNOOZE_CHOICES {
+        append_menu_item(list, popover, label, move || {
+            debug!(?id, label, "Snoozing");
+            crate::send_event(crate::NotifStackEvent::Snoozed(id, until(), None));
+        });
+    }
+}
 
-            // FIXME: So main::NotificationStack::on_post_close_notif is not getting called at all
-            // unless we emit this message to the queue
-            // which makes this closure redundant
+/// Builds the button that opens the list of snooze durations.
+fn build_snooze_menu(id: u32) -> gtk::MenuButton {
+    let list = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let popover = gtk::Popover::builder().child(&list).build();
+    append_snooze_items(&list, &popover, id);
+    gtk::MenuButton::builder()
+        .icon_name("alarm-symbolic")
+        .tooltip_text("Snooze")
+        .css_classes(vec!["snooze-button", "circle-radius"])
+        .popover(&popover)
+        .build()
+}
 
-            let tx = &crate::NOTIF_CHANS.0;
+/// Opens the context menu of a toast at `x`, `y` in `parent`.
+fn popup_context_menu(parent: &gtk::Widget, x: f64, y: f64, id: u32, group_key: &str, text: &str) {
+    let list = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let popover = gtk::Popover::builder()
+        .child(&list)
+        .has_arrow(false)
+        .halign(gtk::Align::Start)
+        .build();
+
+    let key = group_key.to_string();
+    append_menu_item(&list, &popover, "Mute this app", move || {
+        crate::send_event(crate::NotifStackEvent::Muted(key.clone()));
+    });
+    list.append(
+        &gtk::Label::builder()
+            .label("Snooze")
+            .css_classes(vec!["dim-label"])
+            .build(),
+    );
+    append_snooze_items(&list, &popover, id);
+    let (text, widget) = (text.to_string(), parent.downgrade());
+    append_menu_item(&list, &popover, "Copy text", move || {
+        if let Some(widget) = widget.upgrade() {
+            widget.clipboard().set_text(&text);
+        }
+    });
+
+    popover.set_parent(parent);
+    #[allow(clippy::cast_possible_truncation)]
+    popover.set_pointing_to(Some(&gtk::gdk::Rectangle::new(x as i32, y as i32, 1, 1)));
+    // the popover is made for this click only
+    popover.connect_closed(|popover| {
+        let popover = popover.clone();
+        glib::idle_add_local_once(move || popover.unparent());
+    });
+    popover.popup();
+}
 
-            glib::MainContext::default().spawn_local(async move {
-                tx.send(crate::NotifStackEvent::Closed(window_id))
-                    .await
-                    .unwrap();
-            });
+/// Sets the layer shell keyboard mode of the toast window `widget` is in.
+fn set_keyboard_mode(widget: &gtk::Widget, mode: KeyboardMode) {
+    if let Some(window) = (widget.root()).and_then(|root| root.downcast::<gtk::Window>().ok()) {
+        if window.is_layer_window() {
+            window.set_keyboard_mode(mode);
+        }
+    }
+}
 
-            window.close();
+/// Builds the content of the window of a notif group.
+///
+/// Collapsed, this is the latest notif with a count of the others. Expanded, this lists every
+/// notif in the group, the newest first.
+pub fn build_group(key: &str, notifs: &[&Notification], expanded: bool) -> gtk::Box {
+    let container = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    let Some(latest) = notifs.last() else {
+        return container;
+    };
+
+    let toggle_button = |label: &str| {
+        let button = gtk::Button::builder()
+            .label(label)
+            .css_classes(vec!["pill"])
+            .build();
+        let key = key.to_string();
+        button.connect_clicked(move |_| {
+            crate::send_event(crate::NotifStackEvent::GroupToggled(key.clone()));
         });
+        button
+    };
+
+    if !expanded {
+        let close_event = if notifs.len() > 1 {
+            crate::NotifStackEvent::GroupClosed(key.to_string())
+        } else {
+            crate::NotifStackEvent::Closed(latest.id, CloseReason::Dismissed)
+        };
+        container.append(&latest.build_content(close_event));
+        if notifs.len() > 1 {
+            let badge = toggle_button(&format!("+{} more", notifs.len() - 1));
+            badge.set_halign(gtk::Align::End);
+            badge.set_margin_end(10);
+            badge.set_margin_bottom(10);
+            badge.add_css_class("notif-count");
+            container.append(&badge);
+        }
+        return container;
+    }
 
-        action_box.append(&close_button);
+    let header = gtk::Box::builder()
+        .orientation(gtk::Orientation::Horizontal)
+        .spacing(10)
+        .margin_top(10)
+        .margin_start(20)
+        .margin_end(10)
+        .build();
+    let app_name = gtk::Label::builder()
+        .label(&latest.app_name)
+        .halign(gtk::Align::Start)
+        .hexpand(true)
+        .ellipsize(gtk::pango::EllipsizeMode::End)
+        .css_classes(vec!["bold"])
+        .build();
+    let clear_button = gtk::Button::builder()
+        .label("Clear all")
+        .css_classes(vec!["pill"])
+        .build();
+    let key_ = key.to_string();
+    clear_button.connect_clicked(move |_| {
+        crate::send_event(crate::NotifStackEvent::GroupClosed(key_.clone()));
+    });
+    header.append(&app_name);
+    header.append(&toggle_button("Show less"));
+    header.append(&clear_button);
+    container.append(&header);
+
+    let list = gtk::Box::builder()
+        .orientation(gtk::Orientation::Vertical)
+        .build();
+    for notif in notifs.iter().rev() {
+        list.append(&notif.build_content(crate::NotifStackEvent::Closed(
+            notif.id,
+            CloseReason::Dismissed,
+        )));
+    }
+    let max_height = crate::config::get().height * 4;
+    let scroll = gtk::ScrolledWindow::builder()
+        .hscrollbar_policy(gtk::PolicyType::Never)
+        .propagate_natural_height(true)
+        .max_content_height(max_height)
+        .child(&list)
+        .build();
+    container.append(&scroll);
+
+    container
+}
 
-        box_.append(&action_box);
-        window.set_child(Some(&box_));
+/// Anchors and positions a toast window in the stack, according to the current
+/// [crate::config::Config].
+///
+/// `offset` is the distance in pixels from the first toast, i.e. the heights of the windows before
+/// this one and the gaps between them.
+pub fn place_window(window: &libhelium::Window, offset: i32) {
+    if !window.is_layer_window() {
+        return;
+    }
+    let config = crate::config::get();
+
+    // the first toast sits at the configured margin, the next ones are pushed away from the edge
+    let offset = offset + config.margin.y;
+
+    debug!(?offset);
 
-        window
+    let (vertical, horizontal) = (
+        config.corner.vertical_edge(),
+        config.corner.horizontal_edge(),
+    );
+    for edge in [Edge::Top, Edge::Bottom, Edge::Left, Edge::Right] {
+        window.set_anchor(edge, edge == vertical || edge == horizontal);
+        window.set_margin(edge, 0);
     }
+    window.set_margin(vertical, offset);
+    window.set_margin(horizontal, config.margin.x);
 }
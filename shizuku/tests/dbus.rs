@@ -0,0 +1,381 @@
+//! End-to-end tests of the D-Bus interfaces, on a private session bus.
+//!
+//! The daemon runs like `shizukud` does, on a private `dbus-daemon` and a headless GTK display,
+//! and is driven through [NotificationsProxy] from another thread. GTK must stay on the main
+//! thread, hence `harness = false`.
+//!
+//! This needs `dbus-daemon`, and `gtk4-broadwayd` or an existing display. When they are missing,
+//! the suite is reported as ignored, or fails if it was asked for with `--ignored` or
+//! `--include-ignored`.
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_std::{future::timeout, stream::StreamExt};
+use color_eyre::{
+    eyre::{ensure, eyre},
+    Result,
+};
+use gio::prelude::ApplicationExt;
+use shizuku::{
+    dbus::{NotificationClosedStream, NotificationHintsMap, NotificationsProxy},
+    NotifStackEvent,
+};
+use zbus::{dbus_proxy, zvariant::Value};
+
+/// Time to wait for a signal.
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Helper processes, killed when the suite exits.
+static CHILDREN: Mutex<Vec<Child>> = Mutex::new(Vec::new());
+
+#[dbus_proxy(
+    interface = "com.fyralabs.Shizuku",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/com/fyralabs/Shizuku"
+)]
+trait Shizuku {
+    fn dismiss_newest(&self) -> zbus::Result<()>;
+    fn snooze(&self, id: u32, secs: u64) -> zbus::Result<()>;
+}
+
+/// Starts a private session bus, returns its address.
+fn start_bus() -> Option<String> {
+    let mut child = (Command::new("dbus-daemon"))
+        .args(["--session", "--nofork", "--print-address"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| eprintln!("cannot start dbus-daemon: {e}"))
+        .ok()?;
+    let mut address = String::new();
+    BufReader::new(child.stdout.take()?)
+        .read_line(&mut address)
+        .ok()?;
+    CHILDREN.lock().unwrap().push(child);
+    Some(address.trim().to_string())
+}
+
+/// Starts a headless broadway display, or falls back to the current display.
+fn start_display() -> bool {
+    let display = format!(":{}", 10 + std::process::id() % 50);
+    match Command::new("gtk4-broadwayd").arg(&display).spawn() {
+        Ok(child) => {
+            CHILDREN.lock().unwrap().push(child);
+            std::env::set_var("GDK_BACKEND", "broadway");
+            std::env::set_var("BROADWAY_DISPLAY", display);
+            // give it time to listen
+            std::thread::sleep(Duration::from_millis(500));
+            true
+        }
+        Err(e) => {
+            eprintln!("cannot start gtk4-broadwayd: {e}");
+            std::env::var_os("WAYLAND_DISPLAY").is_some() || std::env::var_os("DISPLAY").is_some()
+        }
+    }
+}
+
+/// Keeps the config, state and caches of the daemon in a scratch directory.
+fn isolate_dirs() -> std::io::Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("shizuku-test-{}", std::process::id()));
+    for (var, sub) in [
+        ("XDG_CONFIG_HOME", "config"),
+        ("XDG_STATE_HOME", "state"),
+        ("XDG_CACHE_HOME", "cache"),
+    ] {
+        std::fs::create_dir_all(dir.join(sub))?;
+        std::env::set_var(var, dir.join(sub));
+    }
+    std::fs::create_dir_all(dir.join("config/shizuku"))?;
+    std::fs::write(
+        dir.join("config/shizuku/config.toml"),
+        "[rate_limit]\nburst = 5\nrefill = 0.01\n",
+    )?;
+    Ok(dir)
+}
+
+fn exit(code: i32) -> ! {
+    for mut child in CHILDREN.lock().unwrap().drain(..) {
+        let _ = child.kill();
+    }
+    std::process::exit(code)
+}
+
+/// Gives up on the suite for lack of `what`: ignored, unless it was asked for explicitly.
+fn missing(what: &str) -> ! {
+    let required = std::env::args().any(|arg| arg == "--ignored" || arg == "--include-ignored");
+    if required {
+        println!("cannot run the D-Bus suite: no {what}");
+        exit(1);
+    }
+    println!("test dbus ... ignored, no {what}");
+    println!("\ntest result: ok. 0 passed; 0 failed; 1 ignored");
+    exit(0)
+}
+
+async fn notify(
+    proxy: &NotificationsProxy<'_>,
+    app_name: &str,
+    replaces_id: u32,
+    actions: &[&str],
+    hints: NotificationHintsMap<'_>,
+    expire_timeout: i32,
+) -> zbus::Result<u32> {
+    (proxy.notify(
+        app_name,
+        replaces_id,
+        "",
+        "Summary",
+        "Body",
+        actions,
+        hints,
+        expire_timeout,
+    ))
+    .await
+}
+
+/// Waits for `NotificationClosed` for `id`, returns the reason.
+async fn closed(stream: &mut NotificationClosedStream<'_>, id: u32) -> Result<u32> {
+    timeout(SIGNAL_TIMEOUT, async {
+        while let Some(signal) = stream.next().await {
+            let args = signal.args()?;
+            if args.id == id {
+                return Ok(args.reason);
+            }
+        }
+        Err(eyre!("signal stream ended"))
+    })
+    .await
+    .map_err(|_| eyre!("no NotificationClosed for {id}"))?
+}
+
+async fn capabilities(proxy: &NotificationsProxy<'_>) -> Result<()> {
+    let caps = proxy.get_capabilities().await?;
+    for cap in ["actions", "body", "inline-reply"] {
+        ensure!(caps.iter().any(|c| c == cap), "{cap} missing from {caps:?}");
+    }
+    let (name, _, _, spec) = proxy.get_server_information().await?;
+    ensure!(
+        name == "shizuku" && spec == "1.2",
+        "server is {name} {spec}"
+    );
+    Ok(())
+}
+
+async fn ids_increase(proxy: &NotificationsProxy<'_>) -> Result<()> {
+    let first = notify(proxy, "ids", 0, &[], HashMap::new(), -1).await?;
+    let second = notify(proxy, "ids", 0, &[], HashMap::new(), -1).await?;
+    ensure!(first > 0 && second > first, "ids {first} then {second}");
+    Ok(())
+}
+
+async fn replaces(proxy: &NotificationsProxy<'_>) -> Result<()> {
+    let mut stream = proxy.receive_notification_closed().await?;
+    let id = notify(proxy, "replaces", 0, &[], HashMap::new(), 0).await?;
+    let replaced = notify(proxy, "replaces", id, &[], HashMap::new(), 0).await?;
+    ensure!(replaced == id, "replacing {id} returned {replaced}");
+
+    // the replaced notif is not closed, so the first signal is for the close below
+    proxy.close_notification(id).await?;
+    ensure!(closed(&mut stream, id).await? == 3, "wrong close reason");
+    let again = timeout(Duration::from_millis(500), stream.next()).await;
+    ensure!(again.is_err(), "notif closed twice");
+
+    // replacing a closed or unknown notif gets a new ID, not one that is handed out later
+    let reopened = notify(proxy, "replaces", id, &[], HashMap::new(), 0).await?;
+    ensure!(reopened > id, "replacing closed {id} returned {reopened}");
+    let unknown = reopened + 1000;
+    let fresh = notify(proxy, "replaces", unknown, &[], HashMap::new(), 0).await?;
+    ensure!(
+        fresh == reopened + 1,
+        "replacing unknown {unknown} returned {fresh}"
+    );
+    Ok(())
+}
+
+async fn close_reasons(proxy: &NotificationsProxy<'_>, shizuku: &ShizukuProxy<'_>) -> Result<()> {
+    let mut stream = proxy.receive_notification_closed().await?;
+
+    let id = notify(proxy, "close", 0, &[], HashMap::new(), 0).await?;
+    proxy.close_notification(id).await?;
+    ensure!(closed(&mut stream, id).await? == 3, "closed: wrong reason");
+
+    let id = notify(proxy, "close", 0, &[], HashMap::new(), 1000).await?;
+    ensure!(closed(&mut stream, id).await? == 1, "expired: wrong reason");
+
+    let id = notify(proxy, "close", 0, &[], HashMap::new(), 0).await?;
+    shizuku.dismiss_newest().await?;
+    ensure!(
+        closed(&mut stream, id).await? == 2,
+        "dismissed: wrong reason"
+    );
+    Ok(())
+}
+
+async fn snooze_on_screen_only(
+    proxy: &NotificationsProxy<'_>,
+    shizuku: &ShizukuProxy<'_>,
+) -> Result<()> {
+    let mut stream = proxy.receive_notification_closed().await?;
+    let id = notify(proxy, "snooze", 0, &[], HashMap::new(), 0).await?;
+    shizuku.snooze(id, 60).await?;
+    ensure!(shizuku.snooze(id, 60).await.is_err(), "snoozed {id} twice");
+    // snoozed notifs can still be closed by their app
+    proxy.close_notification(id).await?;
+    ensure!(closed(&mut stream, id).await? == 3, "wrong close reason");
+    ensure!(shizuku.snooze(id, 60).await.is_err(), "snoozed closed {id}");
+    Ok(())
+}
+
+async fn actions(proxy: &NotificationsProxy<'_>) -> Result<()> {
+    let mut invoked = proxy.receive_action_invoked().await?;
+    let mut stream = proxy.receive_notification_closed().await?;
+    let id = notify(
+        proxy,
+        "actions",
+        0,
+        &["default", "Open", "later", "Later"],
+        HashMap::new(),
+        0,
+    )
+    .await?;
+
+    // what clicking the toast does
+    shizuku::send_event(NotifStackEvent::ActionInvoked(id, "later".to_string()));
+    let signal = (timeout(SIGNAL_TIMEOUT, invoked.next()).await)
+        .map_err(|_| eyre!("no ActionInvoked"))?
+        .ok_or_else(|| eyre!("signal stream ended"))?;
+    let args = signal.args()?;
+    ensure!(
+        args.id == id && args.action_key == "later",
+        "invoked {args:?}"
+    );
+    ensure!(closed(&mut stream, id).await? == 2, "wrong close reason");
+    Ok(())
+}
+
+async fn malformed_hints(proxy: &NotificationsProxy<'_>) -> Result<()> {
+    let cases: Vec<NotificationHintsMap> = vec![
+        HashMap::from([("urgency", Value::from("critical"))]),
+        HashMap::from([("image-data", Value::from("not an image"))]),
+        // far fewer pixels than the dimensions say
+        HashMap::from([(
+            "image-data",
+            Value::from((64, 64, 256, true, 8, 4, vec![0_u8; 16])),
+        )]),
+        HashMap::from([("x-kde-urls", Value::from(42_u32))]),
+        HashMap::from([("category", Value::from(1_u8))]),
+    ];
+    for (i, hints) in cases.into_iter().enumerate() {
+        let description = format!("{hints:?}");
+        // an app name each, so that no case eats into the rate limit of another
+        let id = notify(proxy, &format!("malformed-{i}"), 0, &[], hints, 0).await;
+        ensure!(id.is_ok(), "{description} gave {id:?}");
+    }
+    // still alive
+    proxy.get_capabilities().await?;
+    Ok(())
+}
+
+async fn rate_limit(proxy: &NotificationsProxy<'_>) -> Result<()> {
+    // the suite config allows bursts of 5, and "flood" isn't used by any other test
+    for i in 0..5 {
+        let id = notify(proxy, "flood", 0, &[], HashMap::new(), 0).await;
+        ensure!(id.is_ok(), "notif {i} refused: {id:?}");
+    }
+    match notify(proxy, "flood", 0, &[], HashMap::new(), 0).await {
+        Err(zbus::Error::MethodError(name, _, _))
+            if name.as_str() == "org.freedesktop.DBus.Error.LimitsExceeded" =>
+        {
+            Ok(())
+        }
+        result => Err(eyre!("expected LimitsExceeded, got {result:?}")),
+    }
+}
+
+macro_rules! run_tests {
+    ($($test:ident($($arg:expr),*)),* $(,)?) => {{
+        let mut failed = 0;
+        $(
+            match $test($($arg),*).await {
+                Ok(()) => println!("test {} ... ok", stringify!($test)),
+                Err(e) => {
+                    failed += 1;
+                    println!("test {} ... FAILED\n{e:?}", stringify!($test));
+                }
+            }
+        )*
+        failed
+    }};
+}
+
+async fn run_suite() -> Result<usize> {
+    let connection = zbus::Connection::session().await?;
+    let proxy = NotificationsProxy::new(&connection).await?;
+    let shizuku = ShizukuProxy::new(&connection).await?;
+
+    // wait for the daemon to own its name
+    for _ in 0..100 {
+        if proxy.get_server_information().await.is_ok() {
+            break;
+        }
+        async_std::task::sleep(Duration::from_millis(50)).await;
+    }
+
+    Ok(run_tests![
+        capabilities(&proxy),
+        ids_increase(&proxy),
+        replaces(&proxy),
+        close_reasons(&proxy, &shizuku),
+        snooze_on_screen_only(&proxy, &shizuku),
+        actions(&proxy),
+        malformed_hints(&proxy),
+        rate_limit(&proxy),
+    ])
+}
+
+fn main() {
+    let Some(address) = start_bus() else {
+        missing("dbus-daemon");
+    };
+    std::env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+    if !start_display() {
+        missing("display");
+    }
+    // no GPU on CI machines
+    std::env::set_var("GSK_RENDERER", "cairo");
+    let dir = isolate_dirs().expect("cannot create scratch directories");
+
+    shizuku::config::reload();
+    let mut application = shizuku::Application::new();
+    gtk::glib::MainContext::default().spawn_local(async {
+        let connection = zbus::Connection::session().await.unwrap();
+        shizuku::dbus::serve(connection).await.unwrap();
+    });
+
+    std::thread::spawn(move || {
+        let result = async_std::task::block_on(run_suite());
+        let _ = std::fs::remove_dir_all(dir);
+        match result {
+            Ok(0) => {
+                println!("\ntest result: ok");
+                exit(0)
+            }
+            Ok(failed) => {
+                println!("\ntest result: FAILED. {failed} failed");
+                exit(1)
+            }
+            Err(e) => {
+                println!("cannot run the D-Bus suite: {e:?}");
+                exit(1)
+            }
+        }
+    });
+
+    let _hold = application.app.hold();
+    application.run();
+}
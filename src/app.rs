@@ -10,16 +10,14 @@ use crate::runtime;
 
 pub static DBUS_SESSION: OnceLock<zbus::Connection> = OnceLock::new();
 
-/// Initialize the D-Bus session connection asynchronously
-pub(crate) fn init_dbus() {
-    // tokio spawn() does not need to be awaited,
-    // it should resolve on its own
-    runtime().spawn(async {
-        DBUS_SESSION
-            .set(zbus::Connection::session().await.unwrap())
-            .unwrap();
-        tracing::info!("Initialized D-Bus session");
-    });
+/// Initialize the D-Bus session connection, before anything that uses [DBUS_SESSION]
+pub(crate) fn init_dbus() -> Result<()> {
+    let connection = runtime().block_on(Connection::session())?;
+    DBUS_SESSION
+        .set(connection)
+        .map_err(|_| eyre!("D-Bus session is already initialized"))?;
+    tracing::info!("Initialized D-Bus session");
+    Ok(())
 }
 
 // use crate::runtime;
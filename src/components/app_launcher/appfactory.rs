@@ -1,9 +1,14 @@
-use std::rc::Rc;
+use std::{rc::Rc, time::Duration};
 
 use gtk::prelude::*;
 use libhelium::prelude::*;
 use relm4::RelmWidgetExt;
 
+use crate::util::session::{LaunchRequest, SessionManager};
+
+/// How long a tile shows an app as launching if it maps no window.
+const LAUNCH_FEEDBACK_TIMEOUT: Duration = Duration::from_secs(15);
+
 #[derive(Debug)]
 pub struct App {
     pub icon: gio::Icon,
@@ -11,6 +16,13 @@ pub struct App {
     pub keywords: Vec<glib::GString>,
     pub description: String,
     pub deskappinfo: gio::DesktopAppInfo,
+    /// Whether the app was launched from this tile and has no window yet.
+    launching: bool,
+}
+
+#[derive(Debug)]
+pub enum AppMsg {
+    Launch,
 }
 
 // note: we probably don't want to list ALL applications at once,
@@ -26,7 +38,7 @@ pub struct App {
 impl relm4::factory::FactoryComponent for App {
     type Widgets = AppWidgets;
     type Init = gio::DesktopAppInfo;
-    type Input = ();
+    type Input = AppMsg;
     type Output = ();
     type CommandOutput = ();
     type ParentWidget = relm4::gtk::FlowBox;
@@ -43,6 +55,10 @@ impl relm4::factory::FactoryComponent for App {
                 set_valign: gtk::Align::Center,
                 set_is_iconic: true,
                 set_tooltip_text: Some(&self.name),
+                #[watch]
+                set_sensitive: !self.launching,
+                #[watch]
+                set_class_active: ("launching", self.launching),
                 #[wrap(Some)]
                 set_child = &gtk::Box {
                     set_halign: gtk::Align::Fill,
@@ -53,9 +69,20 @@ impl relm4::factory::FactoryComponent for App {
                     // set_margin_bottom: 4,
                     // set_margin_start: 2,
                     // set_margin_end: 2,
-                    gtk::Image {
-                        set_icon_size: gtk::IconSize::Large,
-                        set_from_gicon: &self.icon,
+                    gtk::Overlay {
+                        #[wrap(Some)]
+                        set_child = &gtk::Image {
+                            set_icon_size: gtk::IconSize::Large,
+                            set_from_gicon: &self.icon,
+                        },
+                        add_overlay = &gtk::Spinner {
+                            set_halign: gtk::Align::Center,
+                            set_valign: gtk::Align::Center,
+                            #[watch]
+                            set_visible: self.launching,
+                            #[watch]
+                            set_spinning: self.launching,
+                        },
                     },
                     gtk::Label {
                         set_hexpand: true,
@@ -73,16 +100,40 @@ impl relm4::factory::FactoryComponent for App {
                         inline_css: "caption",
                     },
                 },
-                // ? https://gtk-rs.org/gtk-rs-core/git/docs/gio/prelude/trait.AppInfoExt.html#method.launch
-                connect_clicked[deskappinfo = self.deskappinfo.clone()] => move |_btn| {
-                    if let Err(err) = crate::util::launch_desktop(&deskappinfo) {
+                connect_clicked => AppMsg::Launch,
+            },
+        }
+    }
+
+    fn update(&mut self, msg: Self::Input, sender: relm4::FactorySender<Self>) {
+        match msg {
+            AppMsg::Launch => {
+                if self.launching {
+                    return;
+                }
+                let request = LaunchRequest::new(self.deskappinfo.clone());
+                let handle = match SessionManager::get().launch(request) {
+                    Ok(handle) => handle,
+                    Err(err) => {
                         tracing::error!(?err, "cannot launch app");
+                        return;
                     }
-                }
-            },
+                };
+                self.launching = true;
+                // cleared by update_cmd once the app shows up or gives up
+                sender.oneshot_command(async move {
+                    if !handle.window_mapped(LAUNCH_FEEDBACK_TIMEOUT).await {
+                        tracing::debug!(app_id = handle.app_id, "No window mapped after launch");
+                    }
+                });
+            }
         }
     }
 
+    fn update_cmd(&mut self, (): Self::CommandOutput, _sender: relm4::FactorySender<Self>) {
+        self.launching = false;
+    }
+
     fn init_model(
         init: Self::Init,
         _index: &relm4::factory::DynamicIndex,
@@ -100,6 +151,7 @@ impl relm4::factory::FactoryComponent for App {
             keywords,
             description,
             deskappinfo: init,
+            launching: false,
         }
     }
 }
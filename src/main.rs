@@ -20,12 +20,13 @@ fn env_filter() -> EnvFilter {
 fn main() -> Result<()> {
     dotenvy::dotenv().ok();
     stable_eyre::install()?;
-    app::init_dbus();
 
     // set envar for log to KUMO_LOG inst6ead of RUST_LOG
     tracing_subscriber::fmt()
         .with_env_filter(env_filter())
         .init();
+    app::init_dbus()?;
+    util::session::init_session_manager();
     // let file = std::path::PathBuf::from("/usr/share/applications/Alacritty.desktop");
     // let a = util::gio_launch_desktop_file(&file).unwrap();
     //
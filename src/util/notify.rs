@@ -0,0 +1,126 @@
+//! Notifications from kumo itself, through the freedesktop notification daemon.
+use std::{collections::HashMap, pin::pin};
+
+use futures_util::StreamExt;
+use stable_eyre::Result;
+use zvariant::Value;
+
+use crate::app::DBUS_SESSION;
+
+/// App name kumo sends notifications as.
+const APP_NAME: &str = "Kumo";
+
+#[zbus::proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+pub trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// Sends an error notification with `actions`, as pairs of keys and labels.
+async fn send_error(
+    proxy: &NotificationsProxy<'_>,
+    summary: &str,
+    body: &str,
+    actions: &[(&str, &str)],
+) -> Result<u32> {
+    // critical, so that the error stays until the user saw it
+    let hints = HashMap::from([("urgency", Value::U8(2))]);
+    let actions: Vec<&str> = (actions.iter())
+        .flat_map(|(key, label)| [*key, *label])
+        .collect();
+    Ok(proxy
+        .notify(
+            APP_NAME,
+            0,
+            "dialog-error-symbolic",
+            summary,
+            body,
+            &actions,
+            hints,
+            -1,
+        )
+        .await?)
+}
+
+/// Tells the user something went wrong, returns the notification ID.
+pub async fn error(summary: &str, body: &str) -> Result<u32> {
+    let proxy = NotificationsProxy::new(DBUS_SESSION.wait()).await?;
+    send_error(&proxy, summary, body, &[]).await
+}
+
+/// Tells the user something went wrong, and waits for them to pick one of `actions`, as pairs of
+/// keys and labels. Returns the key of the action, or `None` if the notification was closed.
+pub async fn error_with_actions(
+    summary: &str,
+    body: &str,
+    actions: &[(&str, &str)],
+) -> Result<Option<String>> {
+    enum Event {
+        Action(u32, String),
+        Closed(u32),
+    }
+
+    let proxy = NotificationsProxy::new(DBUS_SESSION.wait()).await?;
+    // listen first, the user may be quick
+    let invoked = (proxy.receive_action_invoked().await?).filter_map(|signal| async move {
+        let args = signal.args().ok()?;
+        Some(Event::Action(*args.id(), args.action_key().to_string()))
+    });
+    let closed = (proxy.receive_notification_closed().await?)
+        .filter_map(|signal| async move { Some(Event::Closed(*signal.args().ok()?.id())) });
+    let mut events = pin!(futures_util::stream::select(invoked, closed));
+
+    let id = send_error(&proxy, summary, body, actions).await?;
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Action(action_id, key) if action_id == id => return Ok(Some(key)),
+            Event::Closed(closed_id) if closed_id == id => return Ok(None),
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Tells the user an app failed to launch, in the background.
+pub fn launch_failed(app_name: String, reason: String) {
+    crate::runtime().spawn(async move {
+        let summary = format!("Could not launch {app_name}");
+        if let Err(e) = error(&summary, &reason).await {
+            tracing::error!(?e, summary, "Cannot send notification");
+        }
+    });
+}
+
+/// Tells the user an app ended abnormally, in the background, and offers to reopen it.
+pub fn app_exited(app_id: String, app_name: String, reason: &'static str) {
+    crate::runtime().spawn(async move {
+        let summary = format!("{app_name} quit unexpectedly");
+        match error_with_actions(&summary, reason, &[("reopen", "Reopen")]).await {
+            Ok(Some(action)) if action == "reopen" => {
+                crate::util::session::SessionManager::get().relaunch(app_id);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!(?e, summary, "Cannot send notification"),
+        }
+    });
+}
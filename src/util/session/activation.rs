@@ -0,0 +1,121 @@
+//! Apps launched through D-Bus activation, with `DBusActivatable=true` in their desktop entry.
+//!
+//! See https://specifications.freedesktop.org/desktop-entry-spec/latest/dbus.html
+//!
+//! Such apps are not spawned, but started by the bus when their well-known name, the app ID, is
+//! called on `org.freedesktop.Application`. The bus starts them through systemd, as a
+//! `dbus-*.service` or the unit named by `SystemdService=`, or by itself without systemd. Once
+//! the call returns, the app owns its name, which gives its PID, and from there its unit.
+use std::{collections::HashMap, time::Duration};
+
+use stable_eyre::{eyre::eyre, Result};
+use zbus::names::BusName;
+use zvariant::Value;
+
+/// How long the app may take to start and handle the call, like the bus' own default.
+pub const ACTIVATION_TIMEOUT: Duration = Duration::from_secs(25);
+
+#[zbus::proxy(interface = "org.freedesktop.Application")]
+pub trait Application {
+    fn activate(&self, platform_data: HashMap<&str, Value<'_>>) -> zbus::Result<()>;
+
+    fn open(&self, uris: &[&str], platform_data: HashMap<&str, Value<'_>>) -> zbus::Result<()>;
+
+    fn activate_action(
+        &self,
+        action_name: &str,
+        parameter: Vec<Value<'_>>,
+        platform_data: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<()>;
+}
+
+/// The object path of an app, from its ID: `/` followed by the ID, with `.` replaced by `/` and
+/// `-` by `_`.
+pub fn object_path(app_id: &str) -> String {
+    let path: String = (app_id.chars())
+        .map(|c| match c {
+            '.' => '/',
+            '-' => '_',
+            c => c,
+        })
+        .collect();
+    format!("/{path}")
+}
+
+/// Whether `app_id` can be activated, it must be a valid well-known bus name.
+pub fn is_activatable(app_id: &str) -> bool {
+    zbus::names::WellKnownName::try_from(app_id).is_ok()
+}
+
+/// A call to make on the app.
+#[derive(Debug, Clone)]
+pub enum Call {
+    Activate,
+    Open(Vec<String>),
+    ActivateAction(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Activation {
+    pub app_id: String,
+    pub call: Call,
+    /// xdg-activation token for the window of the app.
+    pub activation_token: Option<String>,
+}
+
+impl Activation {
+    /// `platform-data` of the call, with the activation token under both the current and the
+    /// older, X11-era key.
+    fn platform_data(&self) -> HashMap<&str, Value<'_>> {
+        (self.activation_token.as_deref())
+            .into_iter()
+            .flat_map(|token| {
+                [
+                    ("activation-token", Value::from(token)),
+                    ("desktop-startup-id", Value::from(token)),
+                ]
+            })
+            .collect()
+    }
+
+    /// Calls the app, which the bus starts if needed, and returns the PID that owns its name.
+    pub async fn activate(&self, conn: &zbus::Connection) -> Result<u32> {
+        let app = ApplicationProxy::builder(conn)
+            .destination(self.app_id.as_str())?
+            .path(object_path(&self.app_id))?
+            .build()
+            .await?;
+        let call = async {
+            match &self.call {
+                Call::Activate => app.activate(self.platform_data()).await,
+                Call::Open(uris) => {
+                    let uris: Vec<&str> = uris.iter().map(String::as_str).collect();
+                    app.open(&uris, self.platform_data()).await
+                }
+                Call::ActivateAction(action) => {
+                    (app.activate_action(action, Vec::new(), self.platform_data())).await
+                }
+            }
+        };
+        tokio::time::timeout(ACTIVATION_TIMEOUT, call)
+            .await
+            .map_err(|_| eyre!("{} did not answer in time", self.app_id))??;
+
+        let dbus = zbus::fdo::DBusProxy::new(conn).await?;
+        Ok((dbus.get_connection_unix_process_id(BusName::try_from(self.app_id.as_str())?)).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_paths() {
+        assert_eq!(object_path("org.gnome.Nautilus"), "/org/gnome/Nautilus");
+        assert_eq!(object_path("io.github.some-app"), "/io/github/some_app");
+        assert!(is_activatable("org.gnome.Nautilus"));
+        assert!(!is_activatable("firefox"));
+        assert!(!is_activatable("org.gnome.9Nautilus"));
+    }
+}
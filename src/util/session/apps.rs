@@ -0,0 +1,323 @@
+//! Per-app launch settings, from `$XDG_CONFIG_HOME/kumo/apps.toml`.
+//!
+//! Apps are keyed on their desktop file ID, without `.desktop`. Settings in `[default]` apply
+//! to apps without their own:
+//!
+//! ```toml
+//! [default]
+//! mode = "scope"
+//!
+//! [apps."org.gnome.Terminal"]
+//! mode = "service"
+//!
+//! # keep a runaway tab from freezing the desktop
+//! [apps."org.mozilla.firefox"]
+//! memory_high = "60%"
+//! memory_max = "8G"
+//! cpu_weight = 50
+//! tasks_max = 4096
+//!
+//! [apps."org.gnome.Boxes"]
+//! slice = "background.slice"
+//! ```
+//!
+//! Resource controls become properties of the unit the app runs in, see
+//! `systemd.resource-control(5)`. The file is read on every launch, so changes apply right away.
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use serde::Deserialize;
+use stable_eyre::{
+    eyre::{bail, eyre},
+    Report, Result,
+};
+use zvariant::{OwnedValue, Value};
+
+/// Slice apps run in by default.
+pub const DEFAULT_SLICE: &str = "app.slice";
+
+/// How apps are started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaunchMode {
+    /// Spawned by kumo, then moved into an `app-*.scope`.
+    #[default]
+    Scope,
+    /// Started by systemd as an `app-*.service`, from the `Exec` line of the desktop entry.
+    Service,
+}
+
+/// A memory limit, in bytes, with a `K`, `M`, `G` or `T` suffix, as a percentage of the
+/// physical memory, or `infinity`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "MemoryValue")]
+pub enum MemoryLimit {
+    Bytes(u64),
+    Percent(f64),
+    Infinity,
+}
+
+/// A [MemoryLimit] as written in the config.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MemoryValue {
+    Bytes(u64),
+    Text(String),
+}
+
+impl TryFrom<MemoryValue> for MemoryLimit {
+    type Error = Report;
+
+    fn try_from(value: MemoryValue) -> Result<Self> {
+        match value {
+            MemoryValue::Bytes(bytes) => Ok(Self::Bytes(bytes)),
+            MemoryValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for MemoryLimit {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s == "infinity" {
+            return Ok(Self::Infinity);
+        }
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.parse()?;
+            if !(0.0..=100.0).contains(&percent) {
+                bail!("Memory limit {s} is not between 0% and 100%");
+            }
+            return Ok(Self::Percent(percent));
+        }
+        let (number, shift) = match s.char_indices().last() {
+            Some((i, 'K')) => (&s[..i], 10),
+            Some((i, 'M')) => (&s[..i], 20),
+            Some((i, 'G')) => (&s[..i], 30),
+            Some((i, 'T')) => (&s[..i], 40),
+            _ => (s, 0),
+        };
+        let number: u64 = number.parse()?;
+        let bytes = (number.checked_mul(1 << shift)).ok_or_else(|| eyre!("{s} is too large"))?;
+        Ok(Self::Bytes(bytes))
+    }
+}
+
+impl MemoryLimit {
+    /// The unit property for this limit on `property`, e.g. `MemoryHigh`.
+    fn property(self, property: &str) -> Result<(String, OwnedValue)> {
+        Ok(match self {
+            Self::Bytes(bytes) => (property.to_string(), Value::U64(bytes).try_into()?),
+            Self::Infinity => (property.to_string(), Value::U64(u64::MAX).try_into()?),
+            // percentages go through the *Scale properties, scaled to u32
+            Self::Percent(percent) => {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let scale = (percent / 100.0 * f64::from(u32::MAX)).round() as u32;
+                (format!("{property}Scale"), Value::U32(scale).try_into()?)
+            }
+        })
+    }
+}
+
+/// A `CPUWeight` or `IOWeight`, from 1 to 10000.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "u64")]
+pub struct Weight(pub u64);
+
+impl TryFrom<u64> for Weight {
+    type Error = Report;
+
+    fn try_from(weight: u64) -> Result<Self> {
+        if !(1..=10000).contains(&weight) {
+            bail!("Weight {weight} is not between 1 and 10000");
+        }
+        Ok(Self(weight))
+    }
+}
+
+/// The name of a slice unit, e.g. `background.slice`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Slice(String);
+
+impl TryFrom<String> for Slice {
+    type Error = Report;
+
+    fn try_from(name: String) -> Result<Self> {
+        let valid = (name.strip_suffix(".slice"))
+            .is_some_and(|prefix| !prefix.is_empty() && !prefix.contains('/'));
+        if !valid {
+            bail!("{name:?} is not the name of a slice unit");
+        }
+        Ok(Self(name))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub mode: Option<LaunchMode>,
+    /// Memory use over which the app is throttled and reclaimed from.
+    pub memory_high: Option<MemoryLimit>,
+    /// Memory use over which the app is killed.
+    pub memory_max: Option<MemoryLimit>,
+    /// Share of CPU time, 100 by default.
+    pub cpu_weight: Option<Weight>,
+    /// Share of IO bandwidth, 100 by default.
+    pub io_weight: Option<Weight>,
+    /// Number of tasks (processes and threads) the app may have.
+    pub tasks_max: Option<u64>,
+    /// Slice to run the app in instead of `app.slice`, e.g. `background.slice`.
+    pub slice: Option<Slice>,
+}
+
+impl AppConfig {
+    /// Settings of `self`, falling back to `default` for those it doesn't have.
+    fn or(self, default: &Self) -> Self {
+        Self {
+            mode: self.mode.or(default.mode),
+            memory_high: self.memory_high.or(default.memory_high),
+            memory_max: self.memory_max.or(default.memory_max),
+            cpu_weight: self.cpu_weight.or(default.cpu_weight),
+            io_weight: self.io_weight.or(default.io_weight),
+            tasks_max: self.tasks_max.or(default.tasks_max),
+            slice: self.slice.or_else(|| default.slice.clone()),
+        }
+    }
+
+    pub fn slice(&self) -> &str {
+        (self.slice.as_ref()).map_or(DEFAULT_SLICE, |slice| slice.0.as_str())
+    }
+
+    /// Unit properties for the resource controls, without the slice.
+    pub fn resource_properties(&self) -> Result<Vec<(String, OwnedValue)>> {
+        let mut properties = Vec::new();
+        if let Some(limit) = self.memory_high {
+            properties.push(limit.property("MemoryHigh")?);
+        }
+        if let Some(limit) = self.memory_max {
+            properties.push(limit.property("MemoryMax")?);
+        }
+        for (property, value) in [
+            ("CPUWeight", self.cpu_weight.map(|weight| weight.0)),
+            ("IOWeight", self.io_weight.map(|weight| weight.0)),
+            ("TasksMax", self.tasks_max),
+        ] {
+            if let Some(value) = value {
+                properties.push((property.to_string(), Value::U64(value).try_into()?));
+            }
+        }
+        Ok(properties)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AppsConfig {
+    pub default: AppConfig,
+    pub apps: HashMap<String, AppConfig>,
+}
+
+impl AppsConfig {
+    pub fn path() -> PathBuf {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .unwrap_or_default()
+            .join("kumo/apps.toml")
+    }
+
+    /// Reads the config, or falls back to the defaults if it is missing or invalid.
+    pub fn load() -> Self {
+        let path = Self::path();
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                tracing::warn!(?e, ?path, "Cannot read app settings");
+                return Self::default();
+            }
+        };
+        toml::from_str(&text)
+            .inspect_err(|e| tracing::warn!(%e, ?path, "Invalid app settings"))
+            .unwrap_or_default()
+    }
+
+    /// Settings of an app, with the defaults filled in.
+    pub fn app(&self, app_id: &str) -> AppConfig {
+        let app = self.apps.get(app_id).cloned().unwrap_or_default();
+        app.or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_memory_limits() {
+        assert_eq!(
+            "512M".parse::<MemoryLimit>().unwrap(),
+            MemoryLimit::Bytes(512 << 20)
+        );
+        assert_eq!(
+            "4096".parse::<MemoryLimit>().unwrap(),
+            MemoryLimit::Bytes(4096)
+        );
+        assert_eq!(
+            "60%".parse::<MemoryLimit>().unwrap(),
+            MemoryLimit::Percent(60.0)
+        );
+        assert_eq!(
+            "infinity".parse::<MemoryLimit>().unwrap(),
+            MemoryLimit::Infinity
+        );
+        assert!("120%".parse::<MemoryLimit>().is_err());
+        assert!("lots".parse::<MemoryLimit>().is_err());
+        assert!("99999999T".parse::<MemoryLimit>().is_err());
+    }
+
+    #[test]
+    fn app_settings_fall_back_to_default() {
+        let config: AppsConfig = toml::from_str(
+            r#"
+            [default]
+            memory_max = "80%"
+            cpu_weight = 100
+
+            [apps."org.mozilla.firefox"]
+            memory_max = 8589934592
+            slice = "background.slice"
+            "#,
+        )
+        .unwrap();
+
+        let firefox = config.app("org.mozilla.firefox");
+        assert_eq!(firefox.memory_max, Some(MemoryLimit::Bytes(8 << 30)));
+        assert_eq!(firefox.cpu_weight, Some(Weight(100)));
+        assert_eq!(firefox.slice(), "background.slice");
+
+        let other = config.app("org.gnome.Evince");
+        assert_eq!(other.memory_max, Some(MemoryLimit::Percent(80.0)));
+        assert_eq!(other.slice(), DEFAULT_SLICE);
+        assert_eq!(other.mode.unwrap_or_default(), LaunchMode::Scope);
+    }
+
+    #[test]
+    fn rejects_invalid_controls() {
+        let parse = |toml: &str| toml::from_str::<AppConfig>(toml);
+        assert_eq!(
+            parse("cpu_weight = 10000").unwrap().cpu_weight,
+            Some(Weight(10000))
+        );
+        assert!(parse("cpu_weight = 0").is_err());
+        assert!(parse("io_weight = 10001").is_err());
+        assert_eq!(
+            parse(r#"slice = "background.slice""#).unwrap().slice(),
+            "background.slice"
+        );
+        assert!(parse(r#"slice = "background""#).is_err());
+        assert!(parse(r#"slice = ".slice""#).is_err());
+        assert!(parse(r#"slice = "user.slice/app.slice""#).is_err());
+    }
+}
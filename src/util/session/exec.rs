@@ -0,0 +1,203 @@
+//! The `Exec` key of desktop entries.
+//!
+//! See https://specifications.freedesktop.org/desktop-entry-spec/latest/exec-variables.html
+use stable_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+
+/// What field codes expand to.
+#[derive(Debug, Default, Clone)]
+pub struct FieldCodes<'a> {
+    /// Files or URIs to open.
+    pub uris: &'a [String],
+    /// The `Icon` key, for `%i`.
+    pub icon: Option<&'a str>,
+    /// The translated `Name` key, for `%c`.
+    pub name: &'a str,
+    /// Location of the desktop file, for `%k`.
+    pub location: Option<&'a str>,
+}
+
+/// Splits an `Exec` value into arguments, following its quoting rules.
+///
+/// Arguments are separated by spaces, and may be quoted with double quotes. In quoted arguments,
+/// `"`, `` ` ``, `$` and `\` are escaped with a backslash.
+pub fn split(exec: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => args.extend(arg.take()),
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '`' | '$' | '\\')) => arg.push(c),
+                            Some(c) => bail!("Invalid escape \\{c} in {exec:?}"),
+                            None => bail!("Unterminated escape in {exec:?}"),
+                        },
+                        Some(c) => arg.push(c),
+                        None => bail!("Unterminated quote in {exec:?}"),
+                    }
+                }
+            }
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Local path of a file URI, or the argument itself if it is already a path.
+fn to_path(uri: &str) -> Option<String> {
+    if uri.starts_with('/') {
+        return Some(uri.to_string());
+    }
+    let path = glib::filename_from_uri(uri).ok()?.0;
+    Some(path.to_string_lossy().into_owned())
+}
+
+/// Expands the field codes in a single argument.
+fn expand_arg(arg: &str, codes: &FieldCodes<'_>, uri: Option<&str>) -> Result<Vec<String>> {
+    // codes that stand for several arguments must be alone
+    match arg {
+        "%F" => return Ok(codes.uris.iter().filter_map(|uri| to_path(uri)).collect()),
+        "%U" => return Ok(codes.uris.to_vec()),
+        "%i" => {
+            return Ok((codes.icon)
+                .map(|icon| vec!["--icon".to_string(), icon.to_string()])
+                .unwrap_or_default())
+        }
+        _ => {}
+    }
+
+    let mut expanded = String::with_capacity(arg.len());
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some('f') => expanded.extend(uri.and_then(to_path)),
+            Some('u') => expanded.push_str(uri.unwrap_or_default()),
+            Some('c') => expanded.push_str(codes.name),
+            Some('k') => expanded.push_str(codes.location.unwrap_or_default()),
+            // deprecated
+            Some('d' | 'D' | 'n' | 'N' | 'v' | 'm') => {}
+            Some(c) => bail!("Invalid field code %{c} in {arg:?}"),
+            None => bail!("Trailing % in {arg:?}"),
+        }
+    }
+    // field codes expanding to nothing are dropped, unlike quoted empty arguments
+    if expanded.is_empty() && !arg.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(vec![expanded])
+}
+
+/// Whether `arg` has one of the field codes `codes`, `%%` being a literal `%`.
+fn has_field_code(arg: &str, codes: &[char]) -> bool {
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' && chars.next().is_some_and(|code| codes.contains(&code)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Turns an `Exec` value into the command lines to run.
+///
+/// Apps taking a single file (`%f` or `%u`) get one command line per file.
+pub fn command_lines(exec: &str, codes: &FieldCodes<'_>) -> Result<Vec<Vec<String>>> {
+    let args = split(exec)?;
+    if args.is_empty() {
+        return Err(eyre!("Empty Exec"));
+    }
+    let single = args.iter().any(|arg| has_field_code(arg, &['f', 'u']));
+    let uris: Vec<Option<&str>> = if single && !codes.uris.is_empty() {
+        codes.uris.iter().map(|uri| Some(uri.as_str())).collect()
+    } else {
+        vec![None]
+    };
+
+    uris.into_iter()
+        .map(|uri| {
+            let mut argv = Vec::with_capacity(args.len());
+            for arg in &args {
+                argv.extend(expand_arg(arg, codes, uri)?);
+            }
+            Ok(argv)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_quoted() {
+        assert_eq!(
+            split(r#"sh -c "echo \"\$HOME\" \\ \`x\`"  two"#).unwrap(),
+            ["sh", "-c", r#"echo "$HOME" \ `x`"#, "two"]
+        );
+        assert_eq!(split(r#"a"b c"d """#).unwrap(), ["ab cd", ""]);
+        assert!(split(r#"a "b"#).is_err());
+        assert!(split(r#""\n""#).is_err());
+    }
+
+    #[test]
+    fn expands_field_codes() {
+        let uris = [
+            "file:///tmp/a%20b.txt".to_string(),
+            "https://fyralabs.com".to_string(),
+        ];
+        let codes = FieldCodes {
+            uris: &uris,
+            icon: Some("editor"),
+            name: "Editor",
+            location: Some("/usr/share/applications/editor.desktop"),
+        };
+
+        assert_eq!(
+            command_lines("editor %U %i --class=%c %k 100%%", &codes).unwrap(),
+            [[
+                "editor",
+                "file:///tmp/a%20b.txt",
+                "https://fyralabs.com",
+                "--icon",
+                "editor",
+                "--class=Editor",
+                "/usr/share/applications/editor.desktop",
+                "100%",
+            ]]
+        );
+        assert_eq!(
+            command_lines("editor %F", &codes).unwrap(),
+            [["editor", "/tmp/a b.txt"]]
+        );
+        assert_eq!(
+            command_lines("editor --open %u", &codes).unwrap(),
+            [
+                ["editor", "--open", "file:///tmp/a%20b.txt"],
+                ["editor", "--open", "https://fyralabs.com"],
+            ]
+        );
+        assert_eq!(
+            command_lines("editor %f %d \"\"", &FieldCodes::default()).unwrap(),
+            [["editor", ""]]
+        );
+        assert_eq!(
+            command_lines("printf %%f %F", &codes).unwrap(),
+            [["printf", "%f", "/tmp/a b.txt"]]
+        );
+        assert!(command_lines("editor %x", &codes).is_err());
+    }
+}
@@ -0,0 +1,261 @@
+//! Abnormal exits of the apps kumo launched.
+//!
+//! The units kumo made are followed through the `PropertiesChanged` signals systemd sends for
+//! them. When one ends because it ran out of memory, was killed by a signal or dumped core, the
+//! user is told with a notification, from which the app can be reopened. Each run of a unit is
+//! reported once; the next job on the unit, e.g. a restart, starts a new run. Units kumo stopped
+//! or killed itself are not reported.
+//!
+//! systemd only tells how the main process of a service ended. Scopes have no main process, so
+//! for the apps kumo spawned, crashes come from their wait status instead, see [process_exited].
+use std::{
+    collections::{HashMap, HashSet},
+    pin::pin,
+};
+
+use futures_util::StreamExt;
+use gio::prelude::AppInfoExt;
+use stable_eyre::Result;
+use zbus::{message::Type, MatchRule, MessageStream};
+use zvariant::OwnedValue;
+
+use super::{
+    unit::{self, AppUnit, LAUNCHER},
+    SessionManager,
+};
+use crate::util::notify;
+
+/// Why an app ended abnormally, from the `Result` property of its unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Killed by the kernel or systemd-oomd for lack of memory.
+    OomKill,
+    /// Killed by a signal it didn't handle.
+    Signal,
+    /// Crashed, leaving a core dump.
+    CoreDump,
+}
+
+impl ExitReason {
+    pub fn from_result(result: &str) -> Option<Self> {
+        match result {
+            "oom-kill" => Some(Self::OomKill),
+            "signal" => Some(Self::Signal),
+            "core-dump" => Some(Self::CoreDump),
+            _ => None,
+        }
+    }
+
+    /// Why a process ended abnormally, from its wait status.
+    ///
+    /// Like for services, `SIGHUP`, `SIGINT`, `SIGTERM` and `SIGPIPE` are clean exits.
+    /// `SIGKILL` is left to the scope, it is how the kernel and systemd-oomd stop apps that ran out
+    /// of memory, which the scope reports as such.
+    pub fn from_wait_status(status: i32) -> Option<Self> {
+        if !libc::WIFSIGNALED(status) {
+            return None;
+        }
+        if libc::WCOREDUMP(status) {
+            return Some(Self::CoreDump);
+        }
+        let clean = [
+            libc::SIGHUP,
+            libc::SIGINT,
+            libc::SIGTERM,
+            libc::SIGPIPE,
+            libc::SIGKILL,
+        ];
+        (!clean.contains(&libc::WTERMSIG(status))).then_some(Self::Signal)
+    }
+
+    /// What happened, for the notification.
+    pub const fn describe(self) -> &'static str {
+        match self {
+            Self::OomKill => "It was stopped because the system ran out of memory.",
+            Self::Signal => "It was killed by a signal.",
+            Self::CoreDump => "It crashed.",
+        }
+    }
+}
+
+/// The runs of units already reported, and the units kumo stopped itself.
+#[derive(Debug, Default)]
+pub struct Exits {
+    reported: HashSet<String>,
+    stopped: HashSet<String>,
+}
+
+impl Exits {
+    /// Marks `unit` as stopped or killed by kumo, its end is not reported.
+    pub fn stopping(&mut self, unit: &str) {
+        self.stopped.insert(unit.to_string());
+    }
+
+    /// Whether the abnormal end of `unit` is to be reported, once per run.
+    fn report(&mut self, unit: &str) -> bool {
+        !self.stopped.contains(unit) && self.reported.insert(unit.to_string())
+    }
+
+    /// Starts a new run of `unit`.
+    fn new_run(&mut self, unit: &str) {
+        self.reported.remove(unit);
+    }
+
+    /// Forgets about `unit` once systemd unloaded it.
+    fn forget(&mut self, unit: &str) {
+        self.reported.remove(unit);
+        self.stopped.remove(unit);
+    }
+}
+
+enum UnitEvent {
+    /// The `Result` property of a unit kumo made changed.
+    Result {
+        unit: String,
+        app_id: String,
+        result: String,
+    },
+    /// A job on a unit is over.
+    JobRemoved(String),
+    /// A unit was unloaded.
+    UnitRemoved(String),
+}
+
+/// The new `Result` of a kumo unit, from a `PropertiesChanged` signal.
+fn result_change(msg: &zbus::Message) -> Option<UnitEvent> {
+    let header = msg.header();
+    let unit = unit::unit_from_path(header.path()?.as_str())?;
+    let app = AppUnit::parse(&unit)?;
+    if app.launcher.as_deref() != Some(LAUNCHER) {
+        return None;
+    }
+    let (interface, changed, _): (String, HashMap<String, OwnedValue>, Vec<String>) =
+        msg.body().deserialize().ok()?;
+    if !matches!(
+        interface.as_str(),
+        "org.freedesktop.systemd1.Scope" | "org.freedesktop.systemd1.Service"
+    ) {
+        return None;
+    }
+    let result = <&str>::try_from(&**changed.get("Result")?).ok()?;
+    Some(UnitEvent::Result {
+        result: result.to_string(),
+        app_id: app.app_id,
+        unit,
+    })
+}
+
+/// Name of the app, from its desktop file.
+fn app_name(app_id: &str) -> String {
+    gio::DesktopAppInfo::new(&format!("{app_id}.desktop")).map_or_else(
+        || app_id.to_string(),
+        |appinfo| appinfo.display_name().into(),
+    )
+}
+
+async fn follow_exits(manager: &'static SessionManager) -> Result<()> {
+    // also makes sure kumo is subscribed, systemd doesn't send unit signals otherwise
+    let systemd = manager.systemd().await?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender("org.freedesktop.systemd1")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .path_namespace(unit::UNIT_PATH_PREFIX.trim_end_matches('/'))?
+        .build();
+    let results = (MessageStream::for_match_rule(rule, &manager.dbus, None).await?)
+        .filter_map(|msg| async move { result_change(&msg.ok()?) });
+    let jobs = (systemd.receive_job_removed().await?).filter_map(|signal| async move {
+        Some(UnitEvent::JobRemoved(signal.args().ok()?.unit().clone()))
+    });
+    let removed = (systemd.receive_unit_removed().await?).filter_map(|signal| async move {
+        Some(UnitEvent::UnitRemoved(signal.args().ok()?.id().clone()))
+    });
+    let events = futures_util::stream::select(results, jobs);
+    let mut events = pin!(futures_util::stream::select(events, removed));
+
+    while let Some(event) = events.next().await {
+        match event {
+            UnitEvent::Result {
+                unit,
+                app_id,
+                result,
+            } => {
+                let Some(reason) = ExitReason::from_result(&result) else {
+                    continue;
+                };
+                // systemd sends every property again whenever one changes
+                if !manager.exits.lock().unwrap().report(&unit) {
+                    continue;
+                }
+                tracing::info!(unit, app_id, result, "App ended abnormally");
+                notify::app_exited(app_id.clone(), app_name(&app_id), reason.describe());
+            }
+            UnitEvent::JobRemoved(unit) => manager.exits.lock().unwrap().new_run(&unit),
+            UnitEvent::UnitRemoved(unit) => manager.exits.lock().unwrap().forget(&unit),
+        }
+    }
+    Ok(())
+}
+
+/// Reports the end of a process kumo spawned for `app_id`, from its wait status. `unit` is the
+/// unit it was moved into, if it got that far.
+pub fn process_exited(
+    manager: &SessionManager,
+    app_id: &str,
+    app_name: &str,
+    unit: Option<&str>,
+    status: i32,
+) {
+    let Some(reason) = ExitReason::from_wait_status(status) else {
+        return;
+    };
+    if unit.is_some_and(|unit| !manager.exits.lock().unwrap().report(unit)) {
+        return;
+    }
+    tracing::info!(app_id, unit, status, "App process ended abnormally");
+    notify::app_exited(app_id.to_string(), app_name.to_string(), reason.describe());
+}
+
+/// Reports abnormal exits of kumo's units for as long as kumo runs.
+pub(super) async fn watch_exits(manager: &'static SessionManager) {
+    if let Err(e) = follow_exits(manager).await {
+        tracing::error!(?e, "Cannot follow app exits");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_reasons_from_wait_status() {
+        // exited with 1, killed by SIGSEGV with and without a core dump, by SIGTERM and SIGKILL
+        assert_eq!(ExitReason::from_wait_status(1 << 8), None);
+        assert_eq!(
+            ExitReason::from_wait_status(libc::SIGSEGV | 0x80),
+            Some(ExitReason::CoreDump)
+        );
+        assert_eq!(
+            ExitReason::from_wait_status(libc::SIGSEGV),
+            Some(ExitReason::Signal)
+        );
+        assert_eq!(ExitReason::from_wait_status(libc::SIGTERM), None);
+        assert_eq!(ExitReason::from_wait_status(libc::SIGKILL), None);
+    }
+
+    #[test]
+    fn reports_each_run_once() {
+        let mut exits = Exits::default();
+        assert!(exits.report("app-kumo-a-1.service"));
+        assert!(!exits.report("app-kumo-a-1.service"));
+        exits.new_run("app-kumo-a-1.service");
+        assert!(exits.report("app-kumo-a-1.service"));
+
+        exits.stopping("app-kumo-b-2.scope");
+        assert!(!exits.report("app-kumo-b-2.scope"));
+        exits.forget("app-kumo-a-1.service");
+        exits.forget("app-kumo-b-2.scope");
+        assert!(exits.reported.is_empty() && exits.stopped.is_empty());
+    }
+}
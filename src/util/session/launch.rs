@@ -0,0 +1,867 @@
+//! The launch service.
+//!
+//! Every app launch in kumo goes through [SessionManager::launch]. By default, the app is spawned
+//! by gio on the GTK main thread, then its PID is handed to a single long-lived [worker] that moves
+//! it into an `app-*.scope` in `app.slice`, over the shared session bus connection. Apps set to
+//! the `service` mode in [apps](super::apps) are started by systemd itself instead, as an
+//! `app-*.service` running the `Exec` line of their desktop entry. Apps with `DBusActivatable=true`
+//! are started by the bus, see [activation](super::activation). Flatpaks and snaps are left to
+//! their own launchers, see [sandbox](super::sandbox). The progress of a launch can be followed
+//! through the returned [LaunchHandle], up to the app mapping a window.
+//!
+//! Launches go through the launch context of the display, so that apps get an xdg-activation
+//! token (`XDG_ACTIVATION_TOKEN` and `DESKTOP_STARTUP_ID`) and their windows get focus.
+//!
+//! When an app cannot be launched or placed in its scope, the user is told with a notification.
+use std::{cell::RefCell, pin::pin, time::Duration};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use gio::prelude::{AppInfoExt, AppLaunchContextExt, DesktopAppInfoExtManual};
+use glib::{object::Cast, VariantDict};
+use stable_eyre::{
+    eyre::{eyre, OptionExt},
+    Report, Result,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, watch},
+    task::JoinHandle,
+};
+use zbus_systemd::systemd1::UnitNewStream;
+
+use super::{
+    activation::{self, Activation, Call},
+    apps::{AppConfig, AppsConfig, LaunchMode},
+    exec, exits,
+    sandbox::{self, Sandbox},
+    unit, JobResult, SessionManager,
+};
+use crate::util::{appid_from_desktop, notify, wayfire};
+
+/// An app to launch.
+#[derive(Debug, Clone)]
+pub struct LaunchRequest {
+    pub appinfo: gio::DesktopAppInfo,
+    /// Files or URIs to open with the app.
+    pub uris: Vec<String>,
+    /// Desktop action to launch instead of the main `Exec`, from `Actions=` in the desktop entry.
+    pub action: Option<String>,
+}
+
+impl LaunchRequest {
+    pub fn new(appinfo: gio::DesktopAppInfo) -> Self {
+        Self {
+            appinfo,
+            uris: Vec::new(),
+            action: None,
+        }
+    }
+
+    #[must_use]
+    pub fn uris(mut self, uris: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.uris = uris.into_iter().map(Into::into).collect();
+        self
+    }
+
+    #[must_use]
+    pub fn action(mut self, action: impl Into<String>) -> Self {
+        self.action = Some(action.into());
+        self
+    }
+
+    /// The app ID, the desktop file ID without `.desktop`.
+    pub fn app_id(&self) -> Option<String> {
+        let id = self.appinfo.id()?;
+        appid_from_desktop(&id)
+    }
+}
+
+/// Progress of a launch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LaunchState {
+    /// Waiting for gio to spawn the app.
+    Starting,
+    /// The app was spawned, and is being moved into its scope.
+    Spawned { pid: u32 },
+    /// The app runs in `unit`, as `pid` if known.
+    Running { pid: Option<u32>, unit: String },
+    /// systemd did not start `unit` in time, or the scope of a Flatpak or snap was not found.
+    /// The app may still run outside of it.
+    TimedOut {
+        pid: Option<u32>,
+        unit: Option<String>,
+    },
+    /// The app runs as `pid`, but could not be placed in its scope, for `reason`.
+    Unplaced { pid: u32, reason: String },
+    /// The app could not be launched.
+    Failed(String),
+}
+
+impl LaunchState {
+    /// Whether the launch is over, one way or the other.
+    pub const fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            Self::Running { .. } | Self::TimedOut { .. } | Self::Unplaced { .. } | Self::Failed(_)
+        )
+    }
+}
+
+/// Follows a launch started with [SessionManager::launch].
+#[derive(Debug, Clone)]
+pub struct LaunchHandle {
+    pub app_id: String,
+    state: watch::Receiver<LaunchState>,
+}
+
+impl LaunchHandle {
+    pub fn state(&self) -> LaunchState {
+        self.state.borrow().clone()
+    }
+
+    /// A receiver notified on every change of the state, e.g. to update the UI.
+    pub fn subscribe(&self) -> watch::Receiver<LaunchState> {
+        self.state.clone()
+    }
+
+    /// Waits for the launch to be over, and returns how it ended.
+    pub async fn finished(&mut self) -> LaunchState {
+        let finished =
+            (self.state.wait_for(LaunchState::is_finished).await).map(|state| state.clone());
+        // the worker is gone, nothing will change anymore
+        finished.unwrap_or_else(|_| self.state())
+    }
+
+    /// Whether `view` is a window of the launched app.
+    ///
+    /// Windows match on their app ID, or on their process being the one spawned or running in the
+    /// unit of the launch, which covers apps whose app ID isn't their desktop file ID.
+    fn owns_view(&self, view: &wayfire::View) -> bool {
+        if view.app_id.eq_ignore_ascii_case(&self.app_id) {
+            return true;
+        }
+        let Some(pid) = view.pid() else {
+            return false;
+        };
+        match &*self.state.borrow() {
+            LaunchState::Spawned { pid: spawned } => *spawned == pid,
+            LaunchState::Running { pid: main, unit }
+            | LaunchState::TimedOut {
+                pid: main,
+                unit: Some(unit),
+            } => *main == Some(pid) || unit::process_unit(pid).as_ref() == Some(unit),
+            LaunchState::TimedOut { pid: main, .. } => *main == Some(pid),
+            LaunchState::Unplaced { pid: main, .. } => *main == pid,
+            LaunchState::Starting | LaunchState::Failed(_) => false,
+        }
+    }
+
+    /// Waits for the app to map a window, for up to `timeout`. Returns whether it did.
+    ///
+    /// Windows are seen through [wayfire]. Without its IPC, or once it stopped, there is no telling
+    /// when the window shows up, so this returns `false` as soon as the launch is over.
+    pub async fn window_mapped(&self, timeout: Duration) -> bool {
+        let mut views = wayfire::mapped_views();
+        let mapped = async {
+            if let Some(views) = &mut views {
+                loop {
+                    match views.recv().await {
+                        Ok(view) if self.owns_view(&view) => return true,
+                        Ok(_) | Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            }
+            // no window to see, the launch being over is all there is to wait for
+            let mut state = self.subscribe();
+            if state.wait_for(LaunchState::is_finished).await.is_ok() {
+                return false;
+            }
+            std::future::pending().await
+        };
+        let mut state = self.subscribe();
+        let over = async {
+            if (state
+                .wait_for(|state| matches!(state, LaunchState::Failed(_)))
+                .await)
+                .is_ok()
+            {
+                return false;
+            }
+            // the worker is gone, nothing will change anymore
+            std::future::pending().await
+        };
+        let first = futures_util::future::select(pin!(mapped), pin!(over));
+        (tokio::time::timeout(timeout, first).await).is_ok_and(|either| either.factor_first().0)
+    }
+}
+
+/// A command line for systemd to start as a service.
+#[derive(Debug, Clone)]
+pub struct ServiceCommand {
+    pub argv: Vec<String>,
+    /// Environment, as `KEY=VALUE`.
+    pub env: Vec<String>,
+    /// xdg-activation token handed to the app in its environment.
+    pub activation_token: Option<String>,
+    /// The `Path` key of the desktop entry.
+    pub working_dir: Option<String>,
+}
+
+/// What the [worker] has to do for a launch.
+#[derive(Debug)]
+pub enum Task {
+    /// Move a process spawned by gio into a new scope.
+    Adopt { pid: u32 },
+    /// Start a new service.
+    Service(ServiceCommand),
+    /// Find the scope a Flatpak or snap launcher, spawned as `pid`, made.
+    Track {
+        sandbox: Sandbox,
+        pid: u32,
+        /// Subscription to `UnitNew`, started as the launcher was spawned.
+        new_units: Option<JoinHandle<Result<UnitNewStream>>>,
+    },
+    /// Start the app through D-Bus, then move it into a new scope if needed.
+    Activate(Activation),
+}
+
+impl Task {
+    /// The activation token kumo got for the app, to give back if the launch fails.
+    fn activation_token(&self) -> Option<&str> {
+        match self {
+            Self::Service(command) => command.activation_token.as_deref(),
+            Self::Activate(activation) => activation.activation_token.as_deref(),
+            Self::Adopt { .. } | Self::Track { .. } => None,
+        }
+    }
+}
+
+/// Work for the [worker], for a launch of `app_id`.
+#[derive(Debug)]
+pub struct LaunchTask {
+    pub app_id: String,
+    /// Name of the app shown to the user.
+    pub app_name: String,
+    pub task: Task,
+    /// Settings of the app, for its resource controls.
+    pub config: AppConfig,
+    state: watch::Sender<LaunchState>,
+}
+
+impl LaunchTask {
+    async fn run(mut self, manager: &'static SessionManager) {
+        let new_units = match &mut self.task {
+            Task::Track {
+                new_units: Some(subscription),
+                ..
+            } => match (subscription.await)
+                .map_err(Report::from)
+                .and_then(|new_units| new_units)
+            {
+                Ok(new_units) => Some(new_units),
+                // the scope is still found from the processes of the launcher, only slower
+                Err(e) => {
+                    tracing::warn!(?e, "Cannot subscribe to new units");
+                    None
+                }
+            },
+            _ => None,
+        };
+        let state = match &self.task {
+            Task::Adopt { pid } => {
+                let result = manager.adopt_app(*pid, &self.app_id, &self.config).await;
+                unit_state(manager, Some(*pid), result).await
+            }
+            Task::Service(command) => {
+                let result = (manager.start_service(&self.app_id, command, &self.config)).await;
+                unit_state(manager, None, result).await
+            }
+            Task::Track { sandbox, pid, .. } => {
+                match manager.sandbox_scope(sandbox, *pid, new_units).await {
+                    Some(unit) => {
+                        if let Err(e) = manager.set_resource_controls(&unit, &self.config).await {
+                            tracing::warn!(?e, unit, "Cannot set resource controls");
+                        }
+                        LaunchState::Running { pid: None, unit }
+                    }
+                    None => {
+                        tracing::warn!(?sandbox, pid, "No scope found for sandboxed app");
+                        LaunchState::TimedOut {
+                            pid: None,
+                            unit: None,
+                        }
+                    }
+                }
+            }
+            Task::Activate(activation) => match activation.activate(&manager.dbus).await {
+                Ok(pid) => self.place_activated(manager, pid).await,
+                Err(e) => {
+                    tracing::error!(?e, app_id = self.app_id, "Failed to activate app");
+                    LaunchState::Failed(e.to_string())
+                }
+            },
+        };
+        if let LaunchState::Failed(reason) = &state {
+            notify::launch_failed(self.app_name.clone(), reason.clone());
+            if let Some(token) = self.task.activation_token() {
+                cancel_token(token.to_string());
+            }
+        }
+        self.state.send_replace(state);
+    }
+
+    /// Moves an app the bus started into a new scope, unless it already runs in a unit of its own.
+    ///
+    /// With systemd, the bus starts apps in a `dbus-*.service` of their own, or in the unit named
+    /// by `SystemdService=`; their resource controls are set in place. Flatpaks and snaps, or apps
+    /// that were already running, may already be in their own app unit too. Without systemd, the
+    /// bus spawns apps in its own unit, from which they are moved into a new scope.
+    async fn place_activated(&self, manager: &'static SessionManager, pid: u32) -> LaunchState {
+        let current = match manager.unit_of(pid).await {
+            Ok(unit) => unit,
+            Err(e) => {
+                tracing::warn!(?e, pid, "Cannot get the unit of activated app");
+                return LaunchState::Unplaced {
+                    pid,
+                    reason: e.to_string(),
+                };
+            }
+        };
+        tracing::debug!(pid, unit = current, "App activated");
+        let own = unit::AppUnit::parse(&current).is_some()
+            || sandbox::snap_app_id(&current).is_some()
+            || (current.ends_with(".service")
+                && manager
+                    .main_pid(&current)
+                    .await
+                    .is_ok_and(|main| main == pid));
+        if own {
+            if let Err(e) = manager.set_resource_controls(&current, &self.config).await {
+                tracing::warn!(?e, unit = current, "Cannot set resource controls");
+            }
+            return LaunchState::Running {
+                pid: Some(pid),
+                unit: current,
+            };
+        }
+        let result = manager.adopt_app(pid, &self.app_id, &self.config).await;
+        unit_state(manager, Some(pid), result).await
+    }
+}
+
+/// The state of a launch once systemd is done with its unit.
+///
+/// When the app already runs as `pid`, failing to place it in its unit is no failure to launch.
+async fn unit_state(
+    manager: &SessionManager,
+    pid: Option<u32>,
+    result: Result<(String, JobResult)>,
+) -> LaunchState {
+    match result {
+        Ok((unit, JobResult::Done)) => {
+            let pid = match pid {
+                Some(pid) => Some(pid),
+                None => (manager.main_pid(&unit).await)
+                    .inspect_err(|e| tracing::warn!(?e, unit, "Cannot get main PID"))
+                    .ok(),
+            };
+            LaunchState::Running { pid, unit }
+        }
+        Ok((unit, JobResult::Timeout)) => {
+            tracing::warn!(?pid, unit, "Timed out waiting for the app unit");
+            LaunchState::TimedOut {
+                pid,
+                unit: Some(unit),
+            }
+        }
+        Ok((unit, JobResult::Failed(result))) => {
+            let reason = format!("systemd could not start {unit}: {result}");
+            unit_failed(pid, reason)
+        }
+        Err(e) => unit_failed(pid, e.to_string()),
+    }
+}
+
+/// The state of a launch whose unit could not be started, for `reason`.
+fn unit_failed(pid: Option<u32>, reason: String) -> LaunchState {
+    match pid {
+        Some(pid) => {
+            tracing::warn!(pid, reason, "App runs outside of its unit");
+            LaunchState::Unplaced { pid, reason }
+        }
+        None => {
+            tracing::error!(reason, "Failed to start the app unit");
+            LaunchState::Failed(reason)
+        }
+    }
+}
+
+/// Runs launch tasks, for as long as kumo runs.
+pub(super) async fn worker(manager: &'static SessionManager) {
+    tracing::info!("Starting launch worker");
+    while let Ok(task) = manager.tasks.1.recv().await {
+        tracing::info!(app_id = task.app_id, task = ?task.task, "Processing launch task");
+        // tasks wait on systemd, don't hold up other launches
+        tokio::spawn(task.run(manager));
+    }
+}
+
+/// Marks a launch as failed, and tells the user.
+fn fail(state: &watch::Sender<LaunchState>, app_name: &str, reason: String) {
+    notify::launch_failed(app_name.to_string(), reason.clone());
+    state.send_replace(LaunchState::Failed(reason));
+}
+
+/// Tells the display that the launch given `token` failed, so that it stops waiting for a window.
+fn cancel_token(token: String) {
+    glib::MainContext::default().invoke(move || launch_context().launch_failed(&token));
+}
+
+/// The `Exec` line of the app, or of the desktop action.
+fn exec_line(request: &LaunchRequest) -> Result<String> {
+    let Some(action) = &request.action else {
+        return Ok(request
+            .appinfo
+            .string("Exec")
+            .ok_or_eyre("No Exec key")?
+            .into());
+    };
+    let path = request.appinfo.filename().ok_or_eyre("No desktop file")?;
+    let keyfile = glib::KeyFile::new();
+    keyfile.load_from_file(path, glib::KeyFileFlags::NONE)?;
+    Ok(keyfile
+        .string(&format!("Desktop Action {action}"), "Exec")?
+        .into())
+}
+
+/// A launch context for the default display, which gives apps an activation token so that their
+/// windows get focus, or a bare one without a display.
+fn launch_context() -> gio::AppLaunchContext {
+    gtk::gdk::Display::default().map_or_else(gio::AppLaunchContext::new, |display| {
+        display.app_launch_context().upcast()
+    })
+}
+
+/// Variables apps get their activation token from.
+const ACTIVATION_TOKEN_VARS: [&str; 2] = ["XDG_ACTIVATION_TOKEN", "DESKTOP_STARTUP_ID"];
+
+/// Variables about kumo's own process and unit, that services get from systemd or not at all.
+const OWN_VARS: [&str; 12] = [
+    "INVOCATION_ID",
+    "JOURNAL_STREAM",
+    "NOTIFY_SOCKET",
+    "MANAGERPID",
+    "MAINPID",
+    "SYSTEMD_EXEC_PID",
+    "LISTEN_PID",
+    "LISTEN_FDS",
+    "LISTEN_FDNAMES",
+    "WATCHDOG_PID",
+    "WATCHDOG_USEC",
+    "KUMO_LOG",
+];
+
+/// The command lines to run for the app, with the field codes expanded, and the environment of
+/// `launch_ctx`.
+fn service_commands(
+    request: &LaunchRequest,
+    launch_ctx: &gio::AppLaunchContext,
+) -> Result<Vec<ServiceCommand>> {
+    let appinfo = &request.appinfo;
+    let icon = appinfo.string("Icon");
+    let name = appinfo.name();
+    let location = appinfo
+        .filename()
+        .map(|path| path.to_string_lossy().into_owned());
+    let codes = exec::FieldCodes {
+        uris: &request.uris,
+        icon: icon.as_deref(),
+        name: &name,
+        location: location.as_deref(),
+    };
+    // a token is only good for a single launch, it must not be passed on
+    let env: Vec<String> = (launch_ctx.environment().into_iter())
+        .map(|var| var.to_string_lossy().into_owned())
+        .filter(|var| {
+            let key = var.split_once('=').map_or(var.as_str(), |(key, _)| key);
+            !ACTIVATION_TOKEN_VARS.contains(&key) && !OWN_VARS.contains(&key)
+        })
+        .collect();
+    let working_dir = appinfo
+        .string("Path")
+        .map(String::from)
+        .filter(|path| !path.is_empty());
+
+    Ok(exec::command_lines(&exec_line(request)?, &codes)?
+        .into_iter()
+        .map(|argv| ServiceCommand {
+            argv,
+            env: env.clone(),
+            activation_token: None,
+            working_dir: working_dir.clone(),
+        })
+        .collect())
+}
+
+/// What the launch paths share for a single launch.
+#[derive(Clone)]
+struct Launch {
+    manager: &'static SessionManager,
+    app_id: String,
+    app_name: String,
+    config: AppConfig,
+    state: watch::Sender<LaunchState>,
+}
+
+impl Launch {
+    /// Hands `task` to the [worker].
+    fn send(&self, task: Task) -> Result<()> {
+        let task = LaunchTask {
+            app_id: self.app_id.clone(),
+            app_name: self.app_name.clone(),
+            task,
+            config: self.config.clone(),
+            state: self.state.clone(),
+        };
+        self.manager.tasks.0.try_send(task)?;
+        Ok(())
+    }
+
+    /// Whether the app should be started as a service rather than spawned.
+    fn wants_service(&self, request: &LaunchRequest) -> bool {
+        if self.config.mode.unwrap_or_default() != LaunchMode::Service {
+            return false;
+        }
+        if Sandbox::detect(&request.appinfo).is_some() {
+            tracing::debug!(
+                app_id = self.app_id,
+                "Sandboxed apps are launched by their own launcher"
+            );
+            return false;
+        }
+        // gio knows how to find a terminal for these
+        if request.appinfo.boolean("Terminal") {
+            tracing::debug!(app_id = self.app_id, "Launching terminal app in a scope");
+            return false;
+        }
+        true
+    }
+
+    /// Whether the app should be started through D-Bus rather than from its `Exec` line.
+    fn wants_activation(&self, request: &LaunchRequest) -> bool {
+        if !request.appinfo.boolean("DBusActivatable") {
+            return false;
+        }
+        if !activation::is_activatable(&self.app_id) {
+            tracing::warn!(
+                app_id = self.app_id,
+                "App ID of D-Bus activatable app is not a bus name, launching it from Exec"
+            );
+            return false;
+        }
+        true
+    }
+
+    fn launch_activation(&self, request: &LaunchRequest) -> Result<()> {
+        let call = match &request.action {
+            Some(action) => Call::ActivateAction(action.clone()),
+            None if request.uris.is_empty() => Call::Activate,
+            None => Call::Open(request.uris.clone()),
+        };
+        let activation_token = (launch_context())
+            .startup_notify_id(&request.appinfo, &[])
+            .map(String::from);
+        self.send(Task::Activate(Activation {
+            app_id: self.app_id.clone(),
+            call,
+            activation_token,
+        }))
+    }
+
+    fn launch_service(&self, request: &LaunchRequest) -> Result<()> {
+        let launch_ctx = launch_context();
+        let commands = service_commands(request, &launch_ctx)?;
+        let mut states = Vec::with_capacity(commands.len());
+        for mut command in commands {
+            // systemd starts the app, so hand it the token ourselves, one per process
+            if let Some(token) = launch_ctx.startup_notify_id(&request.appinfo, &[]) {
+                (command.env).extend(ACTIVATION_TOKEN_VARS.map(|var| format!("{var}={token}")));
+                command.activation_token = Some(token.into());
+            }
+            // every command line is a service of its own
+            let (state, receiver) = watch::channel(LaunchState::Starting);
+            states.push(receiver);
+            Self {
+                state,
+                ..self.clone()
+            }
+            .send(Task::Service(command))?;
+        }
+        crate::runtime().spawn(aggregate(states, self.state.clone()));
+        Ok(())
+    }
+
+    fn launch_scope(&self, request: &LaunchRequest) -> Result<()> {
+        let sandbox = Sandbox::detect(&request.appinfo);
+        // the launcher may make its scope right away, subscribe while it is being spawned,
+        // without holding up the main thread
+        let manager = self.manager;
+        let new_units = (sandbox.is_some())
+            .then(|| crate::runtime().spawn(async move { manager.new_units().await }));
+        let new_units = RefCell::new(new_units);
+        // apps are spawned as children of kumo, except for desktop actions which gio spawns on
+        // its own, so that their wait status tells whether they crashed
+        let child = request.action.is_none();
+        let launch_ctx = launch_context();
+        let launch = self.clone();
+        launch_ctx.connect_launched(move |ctx, _appinfo, v| {
+            tracing::debug!(?ctx, full_context = ?v);
+            let pid: Option<i32> = {
+                let vdict: VariantDict = v.get().unwrap();
+                vdict.lookup("pid").ok().flatten()
+            };
+            let Some(pid) = pid.and_then(|pid| u32::try_from(pid).ok()) else {
+                tracing::warn!(
+                    app_id = launch.app_id,
+                    "No PID for launched app, not adopting it"
+                );
+                return;
+            };
+
+            if child {
+                launch.watch_child(pid, sandbox.is_none());
+            }
+
+            // we want to wrap it in systemd scope too, unless its launcher does
+            launch.state.send_replace(LaunchState::Spawned { pid });
+            let task = match &sandbox {
+                Some(sandbox) => Task::Track {
+                    sandbox: sandbox.clone(),
+                    pid,
+                    // the launches of further files only poll for their scope
+                    new_units: new_units.take(),
+                },
+                None => Task::Adopt { pid },
+            };
+            if let Err(e) = launch.send(task) {
+                tracing::error!(?e, "Failed to send adoption request");
+            }
+        });
+        let launch = self.clone();
+        launch_ctx.connect_launch_failed(move |_ctx, startup_notify_id| {
+            tracing::error!(startup_notify_id, "App failed to launch");
+            fail(
+                &launch.state,
+                &launch.app_name,
+                "The app failed to start.".to_string(),
+            );
+        });
+
+        // actually launch here
+        let uris: Vec<&str> = request.uris.iter().map(String::as_str).collect();
+        match &request.action {
+            Some(action) => request.appinfo.launch_action(action, Some(&launch_ctx)),
+            None => request.appinfo.launch_uris_as_manager(
+                &uris,
+                Some(&launch_ctx),
+                glib::SpawnFlags::SEARCH_PATH | glib::SpawnFlags::DO_NOT_REAP_CHILD,
+                None,
+                None,
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Reaps the app spawned as `pid`, and reports it if it crashed, unless it is only the
+    /// launcher of a sandboxed app.
+    fn watch_child(&self, pid: u32, report: bool) {
+        let launch = self.clone();
+        #[allow(clippy::cast_possible_wrap)]
+        let child = glib::Pid(pid as i32);
+        glib::child_watch_add_local(child, move |_, status| {
+            if !report {
+                return;
+            }
+            let unit = match &*launch.state.borrow() {
+                LaunchState::Running { unit, .. }
+                | LaunchState::TimedOut {
+                    unit: Some(unit), ..
+                } => Some(unit.clone()),
+                _ => None,
+            };
+            exits::process_exited(
+                launch.manager,
+                &launch.app_id,
+                &launch.app_name,
+                unit.as_deref(),
+                status,
+            );
+        });
+    }
+}
+
+/// Follows the launches of several command lines as a single one: running once any of them runs,
+/// and failed only once all of them failed.
+async fn aggregate(states: Vec<watch::Receiver<LaunchState>>, state: watch::Sender<LaunchState>) {
+    let mut finished: FuturesUnordered<_> = (states.into_iter())
+        .map(|mut receiver| async move {
+            let finished =
+                (receiver.wait_for(LaunchState::is_finished).await).map(|state| state.clone());
+            finished.unwrap_or_else(|_| receiver.borrow().clone())
+        })
+        .collect();
+    let mut outcome = None;
+    while let Some(finished) = finished.next().await {
+        match finished {
+            LaunchState::Running { .. } | LaunchState::Unplaced { .. } => {
+                state.send_replace(finished);
+                return;
+            }
+            LaunchState::TimedOut { .. } => outcome = Some(finished),
+            LaunchState::Failed(_) if outcome.is_none() => outcome = Some(finished),
+            _ => {}
+        }
+    }
+    if let Some(outcome) = outcome {
+        state.send_replace(outcome);
+    }
+}
+
+pub(super) fn launch(
+    manager: &'static SessionManager,
+    request: LaunchRequest,
+) -> Result<LaunchHandle> {
+    let app_id = (request.app_id()).ok_or_else(|| eyre!("Could not get app ID of {request:?}"))?;
+    tracing::info!(app_id, action = ?request.action, uris = ?request.uris, "Launching app");
+    let (state, receiver) = watch::channel(LaunchState::Starting);
+    let launch = Launch {
+        manager,
+        app_name: request.appinfo.display_name().to_string(),
+        config: AppsConfig::load().app(&app_id),
+        app_id,
+        state,
+    };
+
+    let launched = if launch.wants_activation(&request) {
+        launch.launch_activation(&request)
+    } else if launch.wants_service(&request) {
+        launch.launch_service(&request)
+    } else {
+        launch.launch_scope(&request)
+    };
+    if let Err(e) = launched {
+        fail(&launch.state, &launch.app_name, e.to_string());
+        return Err(e);
+    }
+
+    Ok(LaunchHandle {
+        app_id: launch.app_id,
+        state: receiver,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESKTOP_ENTRY: &str = "[Desktop Entry]
+Type=Application
+Name=Editor
+Icon=editor
+Exec=true --open %u
+Path=/tmp
+Actions=new-window;
+
+[Desktop Action new-window]
+Name=New Window
+Exec=true --new-window
+";
+
+    #[test]
+    fn service_command_lines() {
+        let dir = std::env::temp_dir().join(format!("kumo-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("org.example.Editor.desktop");
+        std::fs::write(&path, DESKTOP_ENTRY).unwrap();
+        let appinfo = gio::DesktopAppInfo::from_filename(&path).unwrap();
+
+        let launch_ctx = gio::AppLaunchContext::new();
+        launch_ctx.setenv("EDITOR_THEME", "dark");
+        launch_ctx.setenv("INVOCATION_ID", "0123456789abcdef");
+        launch_ctx.setenv("XDG_ACTIVATION_TOKEN", "used");
+
+        let request = LaunchRequest::new(appinfo.clone()).uris(["file:///tmp/a", "file:///tmp/b"]);
+        let commands = service_commands(&request, &launch_ctx).unwrap();
+        let argvs: Vec<_> = commands.iter().map(|command| &command.argv).collect();
+        assert_eq!(
+            argvs,
+            [
+                ["true", "--open", "file:///tmp/a"],
+                ["true", "--open", "file:///tmp/b"],
+            ]
+        );
+        assert_eq!(commands[0].working_dir.as_deref(), Some("/tmp"));
+        let env = &commands[0].env;
+        assert!(env.iter().any(|var| var == "EDITOR_THEME=dark"));
+        assert!(!env.iter().any(|var| var.starts_with("INVOCATION_ID=")));
+        assert!(!env
+            .iter()
+            .any(|var| var.starts_with("XDG_ACTIVATION_TOKEN=")));
+
+        let request = LaunchRequest::new(appinfo).action("new-window");
+        let commands = service_commands(&request, &launch_ctx).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].argv, ["true", "--new-window"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn running_apps_are_not_failed_launches() {
+        assert_eq!(
+            unit_failed(Some(42), "no".to_string()),
+            LaunchState::Unplaced {
+                pid: 42,
+                reason: "no".to_string()
+            }
+        );
+        assert_eq!(
+            unit_failed(None, "no".to_string()),
+            LaunchState::Failed("no".to_string())
+        );
+    }
+
+    #[test]
+    fn aggregates_command_lines() {
+        let outcome = |states: Vec<LaunchState>| {
+            let receivers = (states.into_iter())
+                .map(|state| watch::channel(state).1)
+                .collect();
+            let (state, receiver) = watch::channel(LaunchState::Starting);
+            crate::runtime().block_on(aggregate(receivers, state));
+            receiver
+        };
+        let running = LaunchState::Running {
+            pid: Some(42),
+            unit: "app-editor@1.service".to_string(),
+        };
+        let timed_out = LaunchState::TimedOut {
+            pid: None,
+            unit: None,
+        };
+        let failed = LaunchState::Failed("no".to_string());
+
+        assert_eq!(
+            *outcome(vec![failed.clone(), running.clone()]).borrow(),
+            running
+        );
+        assert_eq!(
+            *outcome(vec![timed_out.clone(), failed.clone()]).borrow(),
+            timed_out
+        );
+        assert_eq!(
+            *outcome(vec![failed.clone(), failed.clone()]).borrow(),
+            failed
+        );
+    }
+}
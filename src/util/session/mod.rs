@@ -0,0 +1,388 @@
+//! Systemd session management
+use std::{pin::pin, sync::OnceLock, time::Duration};
+
+use futures_util::StreamExt;
+use stable_eyre::{
+    eyre::{eyre, OptionExt},
+    Result,
+};
+use zvariant::{OwnedValue, Value};
+
+use crate::{app::DBUS_SESSION, runtime};
+
+pub mod activation;
+pub mod apps;
+pub mod exec;
+pub mod exits;
+pub mod launch;
+pub mod running;
+pub mod sandbox;
+pub mod unit;
+
+pub use launch::{LaunchHandle, LaunchRequest, LaunchState};
+pub use running::{RunningApp, RunningApps};
+
+pub struct SessionManager {
+    pub dbus: zbus::Connection,
+    /// Apps running in the session, followed once [init_session_manager] ran.
+    pub running: RunningApps,
+    /// `org.freedesktop.systemd1.Manager` of the user manager, created on first use.
+    systemd: tokio::sync::OnceCell<zbus_systemd::systemd1::ManagerProxy<'static>>,
+    /// Abnormal exits already reported, and units kumo stopped itself, see [exits].
+    exits: std::sync::Mutex<exits::Exits>,
+    /// Launches waiting on systemd, see [launch::worker].
+    tasks: (
+        async_channel::Sender<launch::LaunchTask>,
+        async_channel::Receiver<launch::LaunchTask>,
+    ),
+}
+
+/// How long to wait for systemd to start a unit.
+const JOB_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to look for the scope of a Flatpak or snap, when no new unit woke us up.
+const SCOPE_POLL: Duration = Duration::from_millis(100);
+
+pub static SESSION_MANAGER: OnceLock<SessionManager> = OnceLock::new();
+
+/// Sets up the session manager, its launch worker, the [RunningApps] model, and the
+/// notifications of abnormal app exits.
+///
+/// This needs [DBUS_SESSION], see [crate::app::init_dbus].
+pub(crate) fn init_session_manager() -> &'static SessionManager {
+    let mut created = false;
+    let manager = SESSION_MANAGER.get_or_init(|| {
+        created = true;
+        let conn = (DBUS_SESSION.get())
+            .expect("D-Bus session is initialized before the session manager")
+            .clone();
+
+        SessionManager::new(conn)
+    });
+    if created {
+        runtime().spawn(launch::worker(manager));
+        runtime().spawn(running::watch_units(manager));
+        runtime().spawn(running::poll_usage(manager));
+        runtime().spawn(exits::watch_exits(manager));
+    }
+    manager
+}
+
+/// How a systemd job ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobResult {
+    Done,
+    /// The job failed, with the result systemd gave, e.g. `failed` or `dependency`.
+    Failed(String),
+    /// systemd did not finish the job in time.
+    Timeout,
+}
+
+impl JobResult {
+    fn from_systemd(result: &str) -> Self {
+        match result {
+            "done" => Self::Done,
+            "timeout" => Self::Timeout,
+            result => Self::Failed(result.to_string()),
+        }
+    }
+}
+
+impl SessionManager {
+    pub fn new(dbus: zbus::Connection) -> Self {
+        Self {
+            dbus,
+            running: RunningApps::default(),
+            systemd: tokio::sync::OnceCell::new(),
+            exits: std::sync::Mutex::default(),
+            tasks: async_channel::unbounded(),
+        }
+    }
+
+    /// The session manager set up by [init_session_manager].
+    pub fn get() -> &'static Self {
+        init_session_manager()
+    }
+
+    /// The systemd user manager, shared by all calls.
+    pub async fn systemd(&self) -> Result<&zbus_systemd::systemd1::ManagerProxy<'static>> {
+        Ok(self
+            .systemd
+            .get_or_try_init(|| async {
+                let manager = zbus_systemd::systemd1::ManagerProxy::new(&self.dbus).await?;
+                // systemd only sends JobRemoved and friends to subscribed clients
+                manager.subscribe().await?;
+                Ok::<_, zbus::Error>(manager)
+            })
+            .await?)
+    }
+
+    /// Moves `pid` into a new scope for the app, returns the scope and how starting it went.
+    pub async fn adopt_app(
+        &self,
+        pid: u32,
+        app_identifier: &str,
+        config: &apps::AppConfig,
+    ) -> Result<(String, JobResult)> {
+        let unit = unit::scope_name(app_identifier);
+        let result = self.adopt_scope(pid, &unit, config).await?;
+        Ok((unit, result))
+    }
+
+    pub async fn adopt_scope(
+        &self,
+        pid: u32,
+        unit_id: &str,
+        config: &apps::AppConfig,
+    ) -> Result<JobResult> {
+        let mut pid_array = zbus::zvariant::Array::new(&zbus::zvariant::Signature::U32);
+        pid_array.append(Value::U32(pid))?;
+        let mut properties = vec![
+            ("PIDs".into(), pid_array.try_into()?),
+            (
+                "CollectMode".into(),
+                Value::Str("inactive-or-failed".into()).try_into()?,
+            ),
+            ("Slice".into(), Value::from(config.slice()).try_into()?),
+        ];
+        properties.extend(config.resource_properties()?);
+        self.start_transient_unit(unit_id, properties).await
+    }
+
+    /// Applies the resource controls of `config` to a running unit, until it stops.
+    ///
+    /// The slice of a running unit can't be changed, so it is left alone.
+    pub async fn set_resource_controls(&self, unit: &str, config: &apps::AppConfig) -> Result<()> {
+        let properties = config.resource_properties()?;
+        if properties.is_empty() {
+            return Ok(());
+        }
+        tracing::debug!(unit, ?properties, "Setting resource controls");
+        (self.systemd().await?)
+            .set_unit_properties(unit.into(), true, properties)
+            .await?;
+        Ok(())
+    }
+
+    /// Starts `command` as a new service for the app, returns the service and how starting it went.
+    pub async fn start_service(
+        &self,
+        app_id: &str,
+        command: &launch::ServiceCommand,
+        config: &apps::AppConfig,
+    ) -> Result<(String, JobResult)> {
+        let unit = unit::service_name(app_id);
+        let program = command.argv.first().ok_or_eyre("Empty command line")?;
+        // systemd wants an absolute path
+        let path = glib::find_program_in_path(program)
+            .ok_or_else(|| eyre!("Cannot find {program} in PATH"))?;
+        let exec_start = vec![(
+            path.to_string_lossy().into_owned(),
+            command.argv.clone(),
+            false,
+        )];
+
+        let mut properties: Vec<(String, OwnedValue)> = vec![
+            ("Description".into(), Value::from(app_id).try_into()?),
+            ("Type".into(), Value::from("exec").try_into()?),
+            ("ExecStart".into(), Value::from(exec_start).try_into()?),
+            (
+                "Environment".into(),
+                Value::from(command.env.clone()).try_into()?,
+            ),
+            (
+                "CollectMode".into(),
+                Value::Str("inactive-or-failed".into()).try_into()?,
+            ),
+            ("Slice".into(), Value::from(config.slice()).try_into()?),
+        ];
+        properties.extend(config.resource_properties()?);
+        if let Some(working_dir) = &command.working_dir {
+            properties.push((
+                "WorkingDirectory".into(),
+                Value::from(working_dir.as_str()).try_into()?,
+            ));
+        }
+
+        let result = self.start_transient_unit(&unit, properties).await?;
+        Ok((unit, result))
+    }
+
+    /// The unit `pid` runs in.
+    pub async fn unit_of(&self, pid: u32) -> Result<String> {
+        let path = self.systemd().await?.get_unit_by_pid(pid).await?;
+        let unit = zbus_systemd::systemd1::UnitProxy::builder(&self.dbus)
+            .path(path)?
+            .build()
+            .await?;
+        Ok(unit.id().await?)
+    }
+
+    /// The main process of a service.
+    pub async fn main_pid(&self, unit: &str) -> Result<u32> {
+        let path = self.systemd().await?.get_unit(unit.into()).await?;
+        let service = zbus_systemd::systemd1::ServiceProxy::builder(&self.dbus)
+            .path(path)?
+            .build()
+            .await?;
+        Ok(service.main_pid().await?)
+    }
+
+    /// `UnitNew` signals, to subscribe to before spawning a launcher that makes its own scope.
+    pub async fn new_units(&self) -> Result<zbus_systemd::systemd1::UnitNewStream> {
+        Ok(self.systemd().await?.receive_unit_new().await?)
+    }
+
+    /// Waits for the scope a Flatpak or snap launcher spawned as `pid` makes for the app, returns
+    /// its name.
+    ///
+    /// The scope is the one the launcher or one of its children is moved into, so that several
+    /// instances of the app are told apart. `new_units`, subscribed to as the launcher was
+    /// spawned, wakes this up as soon as a unit is made, otherwise the processes are polled. Apps that are already running may hand
+    /// the launch over to their running instance instead, the launcher then exits without a scope.
+    pub async fn sandbox_scope(
+        &self,
+        sandbox: &sandbox::Sandbox,
+        pid: u32,
+        mut new_units: Option<zbus_systemd::systemd1::UnitNewStream>,
+    ) -> Option<String> {
+        let found = tokio::time::timeout(JOB_TIMEOUT, async {
+            loop {
+                let tree = unit::process_tree(pid);
+                if tree.is_empty() {
+                    tracing::debug!(pid, "Launcher exited without a scope");
+                    return None;
+                }
+                let scope = (tree.into_iter())
+                    .filter_map(unit::process_unit)
+                    .find(|unit| sandbox.owns_unit(unit));
+                if scope.is_some() {
+                    return scope;
+                }
+
+                // processes are moved into a scope right after it is made
+                let poll = tokio::time::sleep(SCOPE_POLL);
+                if let Some(units) = &mut new_units {
+                    let next = futures_util::future::select(pin!(units.next()), pin!(poll));
+                    let ended = matches!(next.await, futures_util::future::Either::Left((None, _)));
+                    if ended {
+                        new_units = None;
+                    }
+                } else {
+                    poll.await;
+                }
+            }
+        });
+        found.await.unwrap_or(None)
+    }
+
+    /// Starts a transient unit, and waits for systemd to be done with it.
+    #[tracing::instrument(skip(self, properties))]
+    pub async fn start_transient_unit(
+        &self,
+        unit_id: &str,
+        properties: Vec<(String, OwnedValue)>,
+    ) -> Result<JobResult> {
+        self.run_job(|manager| {
+            // "replace" starts the unit and its dependencies, possibly replacing already queued
+            // jobs that conflict with it
+            manager.start_transient_unit(unit_id.into(), "replace".into(), properties, Vec::new())
+        })
+        .await
+    }
+
+    /// Enqueues a job with `enqueue`, and waits for systemd to be done with it.
+    //
+    // StartTransientUnit() returns the newly created job object which has been
+    // enqueued for asynchronous activation. Callers that want to track the
+    // outcome of the actual start operation need to monitor the result of this
+    // job. This can be achieved in a race-free manner by first subscribing to
+    // the JobRemoved() signal, then calling StartUnit() and using the returned
+    // job object to filter out unrelated JobRemoved() signals, until the
+    // desired one is received, which will then carry the result of the start
+    // operation.
+    async fn run_job<'a, F, Fut>(&'a self, enqueue: F) -> Result<JobResult>
+    where
+        F: FnOnce(&'a zbus_systemd::systemd1::ManagerProxy<'static>) -> Fut,
+        Fut: std::future::Future<Output = zbus::Result<zvariant::OwnedObjectPath>>,
+    {
+        let manager = self.systemd().await?;
+        let mut removed = manager.receive_job_removed().await?;
+        let job = enqueue(manager).await?;
+        tracing::debug!(?job, "Waiting for job");
+
+        let result = tokio::time::timeout(JOB_TIMEOUT, async {
+            while let Some(signal) = removed.next().await {
+                let args = signal.args()?;
+                if *args.job() == job {
+                    return Ok(JobResult::from_systemd(args.result()));
+                }
+            }
+            Err(eyre!("JobRemoved stream ended"))
+        })
+        .await
+        .unwrap_or(Ok(JobResult::Timeout))?;
+        tracing::debug!(?result, "Job removed");
+        Ok(result)
+    }
+
+    /// Stops an app unit, and waits for it to be stopped.
+    #[tracing::instrument(skip(self))]
+    pub async fn stop_app(&self, unit: &str) -> Result<JobResult> {
+        self.exits.lock().unwrap().stopping(unit);
+        self.run_job(|manager| manager.stop_unit(unit.into(), "replace".into()))
+            .await
+    }
+
+    /// Sends `signal` to every process of an app unit, e.g. `SIGKILL` to force quit it.
+    #[tracing::instrument(skip(self))]
+    pub async fn kill_app(&self, unit: &str, signal: i32) -> Result<()> {
+        self.exits.lock().unwrap().stopping(unit);
+        (self.systemd().await?)
+            .kill_unit(unit.into(), "all".into(), signal)
+            .await?;
+        Ok(())
+    }
+
+    /// Restarts the app running in `unit`.
+    ///
+    /// Services are restarted by systemd. Scopes can't be, since systemd didn't start them, so the
+    /// scope is stopped, then the app is launched again.
+    #[tracing::instrument(skip(self))]
+    pub async fn restart_app(&'static self, unit: &str) -> Result<JobResult> {
+        let app = unit::AppUnit::parse(unit);
+        if app.as_ref().is_some_and(|app| !app.scope) {
+            return (self.run_job(|manager| manager.restart_unit(unit.into(), "replace".into())))
+                .await;
+        }
+        let app_id = (app.map(|app| app.app_id))
+            .or_else(|| sandbox::snap_app_id(unit))
+            .ok_or_else(|| eyre!("{unit} is not an app unit"))?;
+        let result = self.stop_app(unit).await?;
+        if result != JobResult::Done {
+            return Ok(result);
+        }
+        self.relaunch(app_id);
+        Ok(result)
+    }
+
+    /// Launches `app_id` again, from the GTK main thread.
+    pub fn relaunch(&'static self, app_id: String) {
+        glib::MainContext::default().invoke(move || {
+            let Some(appinfo) = gio::DesktopAppInfo::new(&format!("{app_id}.desktop")) else {
+                tracing::warn!(app_id, "Cannot relaunch app without a desktop file");
+                return;
+            };
+            if let Err(e) = self.launch(LaunchRequest::new(appinfo)) {
+                tracing::error!(?e, app_id, "Cannot relaunch app");
+            }
+        });
+    }
+
+    /// Launches an app, see [launch].
+    ///
+    /// This must be called on the GTK main thread.
+    pub fn launch(&'static self, request: LaunchRequest) -> Result<LaunchHandle> {
+        launch::launch(self, request)
+    }
+}
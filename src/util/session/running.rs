@@ -0,0 +1,336 @@
+//! The apps running in the session, from their systemd units.
+//!
+//! Every app unit (see [unit](super::unit)) is an entry of [RunningApps], whoever launched it, as
+//! well as the scopes of snaps, which don't follow the convention.
+//! Units are listed once, then followed through the `UnitNew` and `UnitRemoved` signals of the
+//! systemd user manager. Those only tell that a unit was loaded or unloaded, so whether it runs
+//! comes from its `ActiveState`, followed for as long as it is loaded. Memory and CPU usage come
+//! from the cgroup properties of the units, and are refreshed every [USAGE_INTERVAL] while
+//! someone is subscribed to [RunningApps].
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    pin::pin,
+    time::Duration,
+};
+
+use futures_util::StreamExt;
+use stable_eyre::Result;
+use tokio::{sync::watch, task::JoinHandle};
+use zbus::{fdo::PropertiesProxy, names::InterfaceName};
+use zvariant::OwnedObjectPath;
+
+use super::{sandbox, unit::AppUnit, SessionManager};
+
+/// How often memory and CPU usage are refreshed.
+pub const USAGE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Value systemd gives for properties it doesn't know, e.g. without accounting.
+const UNSET: u64 = u64::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunningApp {
+    pub unit: String,
+    pub app_id: String,
+    /// Object path of the unit.
+    pub path: OwnedObjectPath,
+    /// Memory used, in bytes.
+    pub memory: Option<u64>,
+    /// CPU time used since the app started.
+    pub cpu_usage: Option<Duration>,
+}
+
+impl RunningApp {
+    /// The entry for `unit`, if it is an app unit or the scope of a snap.
+    fn new(unit: &str, path: OwnedObjectPath) -> Option<Self> {
+        let app_id = (AppUnit::parse(unit).map(|unit| unit.app_id))
+            .or_else(|| sandbox::snap_app_id(unit))?;
+        Some(Self {
+            unit: unit.to_string(),
+            app_id,
+            path,
+            memory: None,
+            cpu_usage: None,
+        })
+    }
+
+    /// The interface with the cgroup properties of the unit.
+    fn interface(&self) -> &'static str {
+        if self.unit.ends_with(".service") {
+            "org.freedesktop.systemd1.Service"
+        } else {
+            "org.freedesktop.systemd1.Scope"
+        }
+    }
+
+    /// The properties of the unit, to read its usage from.
+    async fn properties(&self, conn: &zbus::Connection) -> Result<PropertiesProxy<'static>> {
+        Ok(PropertiesProxy::builder(conn)
+            .destination("org.freedesktop.systemd1")?
+            .path(self.path.clone())?
+            .build()
+            .await?)
+    }
+
+    /// Reads the memory and CPU usage of the unit.
+    async fn usage(
+        &self,
+        properties: &PropertiesProxy<'_>,
+    ) -> Result<(Option<u64>, Option<Duration>)> {
+        let interface = InterfaceName::try_from(self.interface())?;
+        let read =
+            |value: zvariant::OwnedValue| u64::try_from(value).ok().filter(|value| *value != UNSET);
+        let memory = read(properties.get(interface.clone(), "MemoryCurrent").await?);
+        let cpu = read(properties.get(interface, "CPUUsageNSec").await?);
+        Ok((memory, cpu.map(Duration::from_nanos)))
+    }
+}
+
+/// The apps running in the session, keyed on their unit.
+#[derive(Debug)]
+pub struct RunningApps {
+    apps: watch::Sender<BTreeMap<String, RunningApp>>,
+}
+
+impl Default for RunningApps {
+    fn default() -> Self {
+        Self {
+            apps: watch::Sender::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl RunningApps {
+    /// A receiver notified whenever an app starts or stops, or its usage changes.
+    pub fn subscribe(&self) -> watch::Receiver<BTreeMap<String, RunningApp>> {
+        self.apps.subscribe()
+    }
+
+    pub fn apps(&self) -> Vec<RunningApp> {
+        self.apps.borrow().values().cloned().collect()
+    }
+
+    pub fn get(&self, unit: &str) -> Option<RunningApp> {
+        self.apps.borrow().get(unit).cloned()
+    }
+
+    /// Units of `app_id`.
+    pub fn units_of(&self, app_id: &str) -> Vec<String> {
+        (self.apps.borrow().values())
+            .filter(|app| app.app_id == app_id)
+            .map(|app| app.unit.clone())
+            .collect()
+    }
+
+    fn insert(&self, app: RunningApp) {
+        self.apps.send_if_modified(|apps| {
+            if apps.contains_key(&app.unit) {
+                return false;
+            }
+            tracing::debug!(unit = app.unit, app_id = app.app_id, "App started");
+            apps.insert(app.unit.clone(), app);
+            true
+        });
+    }
+
+    fn remove(&self, unit: &str) {
+        self.apps.send_if_modified(|apps| {
+            let removed = apps.remove(unit).is_some();
+            if removed {
+                tracing::debug!(unit, "App stopped");
+            }
+            removed
+        });
+    }
+}
+
+/// Whether a unit in `active_state` runs.
+fn is_running(active_state: &str) -> bool {
+    matches!(active_state, "active" | "activating" | "reloading")
+}
+
+/// Keeps `app` in the model while its unit runs, for as long as the unit is loaded.
+async fn follow_active_state(manager: &'static SessionManager, app: RunningApp) -> Result<()> {
+    let unit = zbus_systemd::systemd1::UnitProxy::builder(&manager.dbus)
+        .path(app.path.clone())?
+        .build()
+        .await?;
+    let mut changes = unit.receive_active_state_changed().await;
+    let mut active_state = unit.active_state().await?;
+    loop {
+        if is_running(&active_state) {
+            manager.running.insert(app.clone());
+        } else {
+            manager.running.remove(&app.unit);
+        }
+        let Some(change) = changes.next().await else {
+            return Ok(());
+        };
+        active_state = change.get().await?;
+    }
+}
+
+/// Follows `app` in a task of its own, kept in `followed` until its unit is unloaded.
+fn follow(
+    manager: &'static SessionManager,
+    followed: &mut HashMap<String, JoinHandle<()>>,
+    app: RunningApp,
+) {
+    let unit = app.unit.clone();
+    let task = tokio::spawn(async move {
+        if let Err(e) = follow_active_state(manager, app).await {
+            tracing::trace!(?e, "Cannot follow app unit");
+        }
+    });
+    if let Some(previous) = followed.insert(unit, task) {
+        previous.abort();
+    }
+}
+
+enum UnitEvent {
+    New(String, OwnedObjectPath),
+    Removed(String),
+}
+
+async fn follow_units(manager: &'static SessionManager) -> Result<()> {
+    let systemd = manager.systemd().await?;
+    // listen before listing, so that no unit slips through in between
+    let new = (systemd.receive_unit_new().await?).filter_map(|signal| async move {
+        let args = signal.args().ok()?;
+        Some(UnitEvent::New(args.id().clone(), args.unit().clone()))
+    });
+    let removed = (systemd.receive_unit_removed().await?).filter_map(|signal| async move {
+        let args = signal.args().ok()?;
+        Some(UnitEvent::Removed(args.id().clone()))
+    });
+    let mut events = pin!(futures_util::stream::select(new, removed));
+
+    // the loaded app units, each followed by a task of its own
+    let mut followed = HashMap::new();
+    for unit in systemd.list_units().await? {
+        if let Some(app) = RunningApp::new(&unit.0, unit.6.clone()) {
+            follow(manager, &mut followed, app);
+        }
+    }
+
+    while let Some(event) = events.next().await {
+        match event {
+            UnitEvent::New(unit, path) => {
+                if let Some(app) = RunningApp::new(&unit, path) {
+                    follow(manager, &mut followed, app);
+                }
+            }
+            UnitEvent::Removed(unit) => {
+                if let Some(task) = followed.remove(&unit) {
+                    task.abort();
+                }
+                manager.running.remove(&unit);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Follows the app units for as long as kumo runs.
+pub(super) async fn watch_units(manager: &'static SessionManager) {
+    if let Err(e) = follow_units(manager).await {
+        tracing::error!(?e, "Cannot follow running apps");
+    }
+}
+
+async fn refresh_usage(
+    manager: &SessionManager,
+    proxies: &mut HashMap<String, PropertiesProxy<'static>>,
+) {
+    let apps = manager.running.apps();
+    proxies.retain(|unit, _| apps.iter().any(|app| app.unit == *unit));
+    for app in apps {
+        let properties = match proxies.entry(app.unit.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match app.properties(&manager.dbus).await {
+                Ok(properties) => entry.insert(properties),
+                Err(e) => {
+                    tracing::trace!(?e, unit = app.unit, "Cannot read usage");
+                    continue;
+                }
+            },
+        };
+        let usage = match app.usage(properties).await {
+            Ok(usage) => usage,
+            // most likely gone in the meantime
+            Err(e) => {
+                tracing::trace!(?e, unit = app.unit, "Cannot read usage");
+                continue;
+            }
+        };
+        manager.running.apps.send_if_modified(|apps| {
+            let Some(app) = apps.get_mut(&app.unit) else {
+                return false;
+            };
+            let changed = (app.memory, app.cpu_usage) != usage;
+            (app.memory, app.cpu_usage) = usage;
+            changed
+        });
+    }
+}
+
+/// Refreshes memory and CPU usage every [USAGE_INTERVAL], for as long as kumo runs, while
+/// someone is subscribed to the model.
+pub(super) async fn poll_usage(manager: &'static SessionManager) {
+    let mut interval = tokio::time::interval(USAGE_INTERVAL);
+    let mut proxies = HashMap::new();
+    loop {
+        interval.tick().await;
+        if manager.running.apps.receiver_count() == 0 {
+            proxies.clear();
+            continue;
+        }
+        refresh_usage(manager, &mut proxies).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::session::unit;
+
+    #[test]
+    fn follows_app_units() {
+        let path = OwnedObjectPath::try_from("/org/freedesktop/systemd1/unit/app").unwrap();
+        assert!(RunningApp::new("session-2.scope", path.clone()).is_none());
+        let app = RunningApp::new(&unit::scope_name("org.gnome.Evince"), path.clone()).unwrap();
+        assert_eq!(app.app_id, "org.gnome.Evince");
+        let snap = "snap.firefox.firefox-5bd1e2a0-8d4c-4b3a-9f7e-0c1d2e3f4a5b.scope";
+        assert_eq!(
+            RunningApp::new(snap, path).unwrap().app_id,
+            "firefox_firefox"
+        );
+
+        let running = RunningApps::default();
+        let mut receiver = running.subscribe();
+        running.insert(app.clone());
+        assert!(receiver.has_changed().unwrap());
+        receiver.mark_unchanged();
+        // its active state changing again keeps its usage
+        running.insert(app.clone());
+        assert!(!receiver.has_changed().unwrap());
+        assert_eq!(running.units_of("org.gnome.Evince"), [app.unit.clone()]);
+        assert_eq!(running.get(&app.unit), Some(app.clone()));
+
+        running.remove(&app.unit);
+        assert!(receiver.has_changed().unwrap());
+        receiver.mark_unchanged();
+        running.remove(&app.unit);
+        assert!(!receiver.has_changed().unwrap());
+        assert!(running.apps().is_empty());
+    }
+
+    #[test]
+    fn running_states() {
+        assert!(["active", "activating", "reloading"]
+            .into_iter()
+            .all(is_running));
+        assert!(!["inactive", "deactivating", "failed"]
+            .into_iter()
+            .any(is_running));
+    }
+}
@@ -0,0 +1,117 @@
+//! Apps packaged as Flatpaks or snaps.
+//!
+//! Their launchers already put the app in a scope of their own: `app-flatpak-<id>-<n>.scope` for
+//! Flatpak, and `snap.<name>.<app>-<uuid>.scope` for snaps. The PID gio reports is only the
+//! `flatpak run` or `snap run` wrapper, so instead of adopting it, the launch service looks for
+//! the scope the launcher moved the wrapper or its children into.
+use super::unit::escape;
+
+/// Prefix of the desktop files snapd exports.
+const SNAP_DESKTOP_DIR: &str = "/var/lib/snapd/desktop/applications/";
+
+/// Length of the UUID at the end of snap scope names.
+const UUID_LEN: usize = 36;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Sandbox {
+    /// A Flatpak, from the `X-Flatpak` key.
+    Flatpak { app_id: String },
+    /// A snap, from the `X-SnapInstanceName` key or where the desktop file is.
+    Snap { name: String },
+}
+
+impl Sandbox {
+    pub fn detect(appinfo: &gio::DesktopAppInfo) -> Option<Self> {
+        if let Some(app_id) = appinfo.string("X-Flatpak") {
+            return Some(Self::Flatpak {
+                app_id: app_id.into(),
+            });
+        }
+        if let Some(name) = appinfo.string("X-SnapInstanceName") {
+            return Some(Self::Snap { name: name.into() });
+        }
+        // older snapd, desktop files are named <snap>_<app>.desktop
+        let path = appinfo.filename()?;
+        let file = path.to_str()?.strip_prefix(SNAP_DESKTOP_DIR)?;
+        let (name, _) = file.split_once('_')?;
+        Some(Self::Snap {
+            name: name.to_string(),
+        })
+    }
+
+    /// IDs Flatpak may use in scope names, older versions don't escape them.
+    fn flatpak_ids(app_id: &str) -> [String; 2] {
+        [app_id.to_string(), escape(app_id)]
+    }
+
+    /// Whether `unit` is a scope the launcher made for the app.
+    pub fn owns_unit(&self, unit: &str) -> bool {
+        match self {
+            Self::Flatpak { app_id } => Self::flatpak_ids(app_id).iter().any(|id| {
+                (unit.strip_prefix("app-flatpak-"))
+                    .and_then(|unit| unit.strip_prefix(id.as_str()))
+                    .and_then(|unit| unit.strip_prefix('-'))
+                    .and_then(|unit| unit.strip_suffix(".scope"))
+                    .is_some_and(|n| !n.is_empty() && !n.contains('-'))
+            }),
+            Self::Snap { name } => (unit.strip_prefix("snap."))
+                .and_then(|unit| unit.strip_prefix(name.as_str()))
+                .is_some_and(|rest| rest.starts_with('.') && rest.ends_with(".scope")),
+        }
+    }
+}
+
+/// Desktop file ID of the snap app running in `unit`, a `snap.<name>.<app>-<uuid>.scope`.
+///
+/// snapd names the desktop files of its apps `<name>_<app>.desktop`.
+pub fn snap_app_id(unit: &str) -> Option<String> {
+    let rest = unit.strip_prefix("snap.")?.strip_suffix(".scope")?;
+    // the UUID is hyphenated, and so may be app names
+    let (name, app) = rest
+        .get(..rest.len().checked_sub(UUID_LEN + 1)?)?
+        .split_once('.')?;
+    if name.is_empty() || app.is_empty() || !rest[name.len() + 1 + app.len()..].starts_with('-') {
+        return None;
+    }
+    Some(format!("{name}_{app}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_launcher_scopes() {
+        let flatpak = Sandbox::Flatpak {
+            app_id: "org.mozilla.firefox".to_string(),
+        };
+        assert!(flatpak.owns_unit("app-flatpak-org.mozilla.firefox-83521.scope"));
+        assert!(!flatpak.owns_unit("app-flatpak-org.mozilla.Thunderbird-83521.scope"));
+        assert!(!flatpak.owns_unit("app-kumo-org.mozilla.firefox-01J9ZZ.scope"));
+        let dashed = Sandbox::Flatpak {
+            app_id: "io.github.some-app".to_string(),
+        };
+        assert!(dashed.owns_unit("app-flatpak-io.github.some-app-1234.scope"));
+        assert!(dashed.owns_unit("app-flatpak-io.github.some\\x2dapp-1234.scope"));
+
+        let snap = Sandbox::Snap {
+            name: "firefox".to_string(),
+        };
+        assert!(snap.owns_unit("snap.firefox.firefox-5bd1e2a0-8d4c-4b3a-9f7e-0c1d2e3f4a5b.scope"));
+        assert!(!snap.owns_unit("snap.firefoxpwa.firefoxpwa-1.scope"));
+        assert!(!snap.owns_unit("snap.firefox.hook.configure.service"));
+
+        assert_eq!(
+            snap_app_id("snap.firefox.geckodriver-5bd1e2a0-8d4c-4b3a-9f7e-0c1d2e3f4a5b.scope")
+                .as_deref(),
+            Some("firefox_geckodriver")
+        );
+        assert_eq!(
+            snap_app_id("snap.code.url-handler-5bd1e2a0-8d4c-4b3a-9f7e-0c1d2e3f4a5b.scope")
+                .as_deref(),
+            Some("code_url-handler")
+        );
+        assert_eq!(snap_app_id("snap.firefox.hook.configure.service"), None);
+        assert_eq!(snap_app_id("snap.firefox.firefox-1.scope"), None);
+    }
+}
@@ -0,0 +1,252 @@
+//! Names of the units apps run in.
+//!
+//! Names follow the systemd [Desktop Environment Integration] convention:
+//!
+//! - `app[-<launcher>]-<ApplicationID>-<RANDOM>.scope` for scopes, and
+//! - `app[-<launcher>]-<ApplicationID>[@<RANDOM>].service` for services,
+//!
+//! where each component is escaped so that it contains no `-`. Apps launched by kumo use `kumo` as
+//! the launcher, other launchers such as Flatpak use their own.
+//!
+//! [Desktop Environment Integration]: https://systemd.io/DESKTOP_ENVIRONMENTS/
+use std::fmt::Write;
+
+/// Launcher component of the units kumo creates.
+pub const LAUNCHER: &str = "kumo";
+
+/// Escapes `s` to be used as a component of a unit name, like `systemd-escape` does.
+///
+/// Letters, digits, `:`, `_` and `.` (except a leading one) are kept, everything else, including
+/// `-`, is replaced by `\xNN` for each byte.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for (i, byte) in s.bytes().enumerate() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b':' | b'_') || (byte == b'.' && i > 0) {
+            escaped.push(char::from(byte));
+        } else {
+            _ = write!(escaped, "\\x{byte:02x}");
+        }
+    }
+    escaped
+}
+
+/// Reverses [escape], returns `None` for invalid escapes or UTF-8.
+pub fn unescape(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'\\' {
+            let hex = tail.strip_prefix(b"x")?.get(..2)?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[3..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn random() -> String {
+    ulid::Ulid::new().to_string()
+}
+
+/// A new scope name for `app_id`.
+pub fn scope_name(app_id: &str) -> String {
+    format!("app-{LAUNCHER}-{}-{}.scope", escape(app_id), random())
+}
+
+/// A new service name for `app_id`.
+pub fn service_name(app_id: &str) -> String {
+    format!("app-{LAUNCHER}-{}@{}.service", escape(app_id), random())
+}
+
+/// An app unit name, split into its components.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppUnit {
+    pub launcher: Option<String>,
+    pub app_id: String,
+    pub random: Option<String>,
+    /// Whether the unit is a scope rather than a service.
+    pub scope: bool,
+}
+
+impl AppUnit {
+    /// Parses a unit name, returns `None` if it isn't an app unit.
+    pub fn parse(unit: &str) -> Option<Self> {
+        let (name, scope) = if let Some(name) = unit.strip_suffix(".scope") {
+            (name, true)
+        } else {
+            (unit.strip_suffix(".service")?, false)
+        };
+        let name = name.strip_prefix("app-")?;
+
+        let (launcher, app_id, random) = if scope {
+            // the random part is mandatory for scopes
+            match *name.split('-').collect::<Vec<_>>() {
+                [app_id, random] => (None, app_id, Some(random)),
+                [launcher, app_id, random] => (Some(launcher), app_id, Some(random)),
+                _ => return None,
+            }
+        } else {
+            let (name, random) = match name.split_once('@') {
+                Some((name, random)) => (name, Some(random)),
+                None => (name, None),
+            };
+            match *name.split('-').collect::<Vec<_>>() {
+                [app_id] => (None, app_id, random),
+                [launcher, app_id] => (Some(launcher), app_id, random),
+                _ => return None,
+            }
+        };
+        if app_id.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            launcher: match launcher {
+                Some(launcher) => Some(unescape(launcher)?),
+                None => None,
+            },
+            app_id: unescape(app_id)?,
+            random: match random {
+                Some(random) => Some(unescape(random)?),
+                None => None,
+            },
+            scope,
+        })
+    }
+}
+
+/// Prefix of the object paths systemd gives units.
+pub const UNIT_PATH_PREFIX: &str = "/org/freedesktop/systemd1/unit/";
+
+/// The unit name in a unit object path, where every byte but letters and digits is `_xx`.
+pub fn unit_from_path(path: &str) -> Option<String> {
+    let escaped = path.strip_prefix(UNIT_PATH_PREFIX)?;
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'_' {
+            let hex = tail.get(..2)?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The scope or service a process runs in, from its cgroup.
+pub fn process_unit(pid: u32) -> Option<String> {
+    let cgroups = std::fs::read_to_string(format!("/proc/{pid}/cgroup")).ok()?;
+    // the unified hierarchy, e.g. 0::/user.slice/.../app.slice/app-kumo-foo-01J9.scope
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    (path.rsplit('/'))
+        .find(|unit| unit.ends_with(".scope") || unit.ends_with(".service"))
+        .map(String::from)
+}
+
+/// `pid` and all its descendants, or nothing if `pid` is gone.
+pub fn process_tree(pid: u32) -> Vec<u32> {
+    let mut tree = Vec::new();
+    let mut pending = vec![pid];
+    while let Some(pid) = pending.pop() {
+        let Ok(tasks) = std::fs::read_dir(format!("/proc/{pid}/task")) else {
+            continue;
+        };
+        tree.push(pid);
+        // children are listed by the thread that spawned them
+        for task in tasks.flatten() {
+            let children =
+                std::fs::read_to_string(task.path().join("children")).unwrap_or_default();
+            pending.extend(
+                children
+                    .split_whitespace()
+                    .filter_map(|child| child.parse().ok()),
+            );
+        }
+    }
+    tree
+}
+
+/// The app ID of an app unit, see [AppUnit::parse].
+pub fn app_id_from_unit(unit: &str) -> Option<String> {
+    AppUnit::parse(unit).map(|unit| unit.app_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_like_systemd() {
+        assert_eq!(escape("org.gnome.Evince"), "org.gnome.Evince");
+        assert_eq!(escape("kde4-kate"), "kde4\\x2dkate");
+        assert_eq!(escape(".hidden app"), "\\x2ehidden\\x20app");
+        assert_eq!(escape("é"), "\\xc3\\xa9");
+        for id in ["kde4-kate", ".hidden app", "é", "a\\x2d"] {
+            assert_eq!(unescape(&escape(id)).as_deref(), Some(id));
+        }
+        assert_eq!(unescape("bad\\x2"), None);
+        assert_eq!(
+            unit_from_path(
+                "/org/freedesktop/systemd1/unit/app_2dkumo_2dkde4_5cx2dkate_2d01J9_2escope"
+            )
+            .as_deref(),
+            Some("app-kumo-kde4\\x2dkate-01J9.scope")
+        );
+    }
+
+    #[test]
+    fn parses_own_names() {
+        let unit = AppUnit::parse(&scope_name("kde4-kate")).unwrap();
+        assert_eq!(unit.launcher.as_deref(), Some(LAUNCHER));
+        assert_eq!(unit.app_id, "kde4-kate");
+        assert!(unit.scope);
+
+        let unit = AppUnit::parse(&service_name("org.gnome.Evince")).unwrap();
+        assert_eq!(unit.app_id, "org.gnome.Evince");
+        assert!(unit.random.is_some() && !unit.scope);
+    }
+
+    #[test]
+    fn parses_other_launchers() {
+        assert_eq!(
+            AppUnit::parse("app-flatpak-org.mozilla.firefox-12345.scope"),
+            Some(AppUnit {
+                launcher: Some("flatpak".to_string()),
+                app_id: "org.mozilla.firefox".to_string(),
+                random: Some("12345".to_string()),
+                scope: true,
+            })
+        );
+        assert_eq!(
+            app_id_from_unit("app-gnome-org.gnome.Terminal-4321.scope").as_deref(),
+            Some("org.gnome.Terminal")
+        );
+        assert_eq!(
+            app_id_from_unit("app-org.gnome.Terminal.service").as_deref(),
+            Some("org.gnome.Terminal")
+        );
+        assert_eq!(app_id_from_unit("app-org.gnome.Terminal.scope"), None);
+        assert_eq!(app_id_from_unit("session-2.scope"), None);
+        assert_eq!(app_id_from_unit("snap.firefox.firefox-1234.scope"), None);
+    }
+
+    #[test]
+    fn finds_child_processes() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let tree = process_tree(std::process::id());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(tree[0], std::process::id());
+        assert!(tree.contains(&child.id()));
+        assert!(process_tree(u32::MAX).is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+//! Window events from Wayfire, through its IPC socket.
+//!
+//! This needs the `ipc` and `ipc-rules` plugins, which expose the socket as `$WAYFIRE_SOCKET`.
+//! Messages are JSON, each preceded by its length as a 32-bit little-endian integer.
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    sync::{Mutex, OnceLock},
+};
+
+use serde::{de::DeserializeOwned, Deserialize};
+use stable_eyre::{eyre::bail, Result};
+use tokio::sync::broadcast;
+
+/// A window, as Wayfire describes it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct View {
+    pub id: u64,
+    /// The process of the client, or -1 if unknown.
+    #[serde(default = "unknown_pid")]
+    pub pid: i64,
+    #[serde(rename = "app-id", default)]
+    pub app_id: String,
+    /// `toplevel` for app windows, `background`, `panel` or `overlay` for shell surfaces.
+    #[serde(rename = "type", default)]
+    pub kind: String,
+}
+
+const fn unknown_pid() -> i64 {
+    -1
+}
+
+impl View {
+    pub fn pid(&self) -> Option<u32> {
+        u32::try_from(self.pid).ok().filter(|pid| *pid > 0)
+    }
+}
+
+/// The reply to a request.
+#[derive(Deserialize)]
+struct Reply {
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Event {
+    event: Option<String>,
+    view: Option<View>,
+}
+
+fn send(stream: &mut UnixStream, message: &serde_json::Value) -> Result<()> {
+    let message = serde_json::to_vec(message)?;
+    stream.write_all(&u32::try_from(message.len())?.to_le_bytes())?;
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+fn receive<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<T> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut message = vec![0; usize::try_from(u32::from_le_bytes(len))?];
+    stream.read_exact(&mut message)?;
+    Ok(serde_json::from_slice(&message)?)
+}
+
+fn watch_mapped(mut stream: UnixStream, views: &broadcast::Sender<View>) -> Result<()> {
+    send(
+        &mut stream,
+        &serde_json::json!({
+            "method": "window-rules/events/watch",
+            "data": { "events": ["view-mapped"] },
+        }),
+    )?;
+    // without `ipc-rules`, the method is unknown
+    if let Reply { error: Some(error) } = receive(&mut stream)? {
+        bail!("Cannot watch mapped views: {error}");
+    }
+    loop {
+        let Event { event, view } = receive(&mut stream)?;
+        if let (Some("view-mapped"), Some(view)) = (event.as_deref(), view) {
+            if view.kind == "toplevel" {
+                // nobody may be listening, that's fine
+                _ = views.send(view);
+            }
+        }
+    }
+}
+
+static MAPPED_VIEWS: OnceLock<Mutex<Option<broadcast::Sender<View>>>> = OnceLock::new();
+
+/// App windows mapped from now on, or `None` without the Wayfire IPC socket.
+///
+/// The socket is read from a thread of its own, started on first use. Once that thread stops,
+/// e.g. without the `ipc-rules` plugin, the receivers are closed and this returns `None`.
+pub fn mapped_views() -> Option<broadcast::Receiver<View>> {
+    let sender = MAPPED_VIEWS.get_or_init(|| Mutex::new(watch()));
+    sender
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(broadcast::Sender::subscribe)
+}
+
+/// Connects to the socket, and starts the thread reading it.
+fn watch() -> Option<broadcast::Sender<View>> {
+    let path = std::env::var_os("WAYFIRE_SOCKET")?;
+    let stream = (UnixStream::connect(&path))
+        .inspect_err(|e| tracing::warn!(?e, ?path, "Cannot connect to Wayfire IPC"))
+        .ok()?;
+    let (sender, _) = broadcast::channel(16);
+    let views = sender.clone();
+    std::thread::Builder::new()
+        .name("wayfire-ipc".into())
+        .spawn(move || {
+            if let Err(e) = watch_mapped(stream, &views) {
+                tracing::warn!(?e, "Stopped watching Wayfire windows");
+            }
+            // drop every sender, so that receivers know no window will come anymore
+            MAPPED_VIEWS.wait().lock().unwrap().take();
+        })
+        .inspect_err(|e| tracing::error!(?e, "Cannot start Wayfire IPC thread"))
+        .ok()?;
+    Some(sender)
+}